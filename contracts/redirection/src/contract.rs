@@ -1,21 +1,32 @@
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
 use cosmwasm_std::{DepsMut, Env, MessageInfo, Reply, Response};
-use cw2::set_contract_version;
+use cw2::{get_contract_version, set_contract_version};
 
-use crate::execute::{update_config, deposit_pool, withdraw_pool};
+use crate::execute::{
+    update_config, execute_config_change, cancel_config_change,
+    grant_role, revoke_role, propose_admin, accept_admin, cancel_admin_proposal,
+    pause, set_emergency_mode, resolve_discrepancy, recover_tokens,
+    deposit_pool, withdraw_pool, emergency_withdraw, set_donation_mode,
+    set_donation_goal, set_give_schedule, request_withdraw, claim_withdraw,
+    execute_claim,
+};
 use crate::internal_calls::{
     deposit_initial, deposit_more, swap_back_aust, 
-    swap_aust_ust, withdraw_send
+    swap_aust_ust, emergency_redeem, withdraw_send
 };
 use crate::replies::{
     make_new_user_struct, deposit_then_update_user, 
     get_new_user_state_dep, get_new_user_state_wit, 
-    withdraw_then_update_user
+    withdraw_then_update_user, emergency_withdraw_then_update_user,
 };
 
-use crate::msg::{ExecuteMsg, InstantiateMsg};
-use crate::state::{Config, CONFIG};
+use crate::helpers::validate_percentage_bounds;
+use crate::migrations::{parse_version, run_migrations};
+use crate::msg::{ExecuteMsg, InstantiateMsg, MigrateMsg};
+use crate::state::{
+    Config, Totals, CONFIG, MAX_EARLY_EXIT_PENALTY_BPS, MAX_PROTOCOL_FEE_BPS, MAX_THETA, TOTALS,
+};
 use crate::error::ContractError;
 
 // version info for migration info
@@ -25,12 +36,27 @@ const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn instantiate(
     deps: DepsMut,
-    _env: Env,
+    env: Env,
     _info: MessageInfo,
     msg: InstantiateMsg,
 ) -> Result<Response, ContractError> {
     set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
 
+    if msg.theta > MAX_THETA {
+        return Err(ContractError::InvalidTheta { max: MAX_THETA });
+    }
+    validate_percentage_bounds(msg.min_percentage, msg.max_percentage)?;
+    if msg.protocol_fee_bps > MAX_PROTOCOL_FEE_BPS {
+        return Err(ContractError::InvalidProtocolFee { max: MAX_PROTOCOL_FEE_BPS });
+    }
+    if msg.early_exit_penalty_bps.unwrap_or_default() > MAX_EARLY_EXIT_PENALTY_BPS {
+        return Err(ContractError::InvalidEarlyExitPenalty { max: MAX_EARLY_EXIT_PENALTY_BPS });
+    }
+    let fee_collector = msg
+        .fee_collector
+        .map(|addr| deps.api.addr_validate(addr.as_str()))
+        .transpose()?;
+
     CONFIG.save(
         deps.storage,
         &Config {
@@ -39,12 +65,53 @@ pub fn instantiate(
             anchor_market_address: deps.api.addr_validate(&msg.anchor_market_address.to_string())?,
             aust_token_address: deps.api.addr_validate(&msg.aust_token_address.to_string())?,
             theta: msg.theta,
+            timelock_delay: msg.timelock_delay,
+            min_deposit: msg.min_deposit,
+            min_percentage: msg.min_percentage,
+            max_percentage: msg.max_percentage,
+            max_deposit_per_user: msg.max_deposit_per_user,
+            max_total_deposits: msg.max_total_deposits,
+            protocol_fee_bps: msg.protocol_fee_bps,
+            fee_collector,
+            fee_target: msg.fee_target,
+            early_exit_penalty_bps: msg.early_exit_penalty_bps,
+            withdraw_cooldown: msg.withdraw_cooldown,
         },
     )?;
+    TOTALS.save(deps.storage, &Totals::default(), env.block.height)?;
 
     Ok(Response::new().add_attribute("method", "instantiate"))
 }
 
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn migrate(mut deps: DepsMut, env: Env, _msg: MigrateMsg) -> Result<Response, ContractError> {
+    let stored = get_contract_version(deps.storage)?;
+    if stored.contract != CONTRACT_NAME {
+        return Err(ContractError::WrongContract {
+            expected: CONTRACT_NAME.to_string(),
+            found: stored.contract,
+        });
+    }
+
+    let stored_version = parse_version(&stored.version)?;
+    let current_version = parse_version(CONTRACT_VERSION)?;
+    if stored_version > current_version {
+        return Err(ContractError::CannotDowngrade {
+            stored: stored.version,
+            current: CONTRACT_VERSION.to_string(),
+        });
+    }
+
+    let applied = run_migrations(&mut deps, &env, &stored_version, &current_version)?;
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "migrate")
+        .add_attribute("from_version", stored.version)
+        .add_attribute("to_version", CONTRACT_VERSION)
+        .add_attribute("applied", applied.join(",")))
+}
+
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> Result<Response, ContractError> {
     match msg.id {
@@ -53,6 +120,7 @@ pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> Result<Response, ContractEr
         2 => deposit_then_update_user(deps, env, msg.result),
         3 => get_new_user_state_wit(deps, env, msg.result),
         4 => withdraw_then_update_user(deps, env, msg.result),
+        5 => emergency_withdraw_then_update_user(deps, env, msg.result),
         _ => Err(ContractError::Unauthorized {}),
     }
 }
@@ -66,39 +134,59 @@ pub fn execute(
 ) -> Result<Response, ContractError> {
     match msg {
         /* Three Entry Points */
-        ExecuteMsg::UpdateConfig(msg) => update_config(deps, info, msg),
-        ExecuteMsg::DepositPool { percentage } => deposit_pool(deps, env, info, percentage),
+        ExecuteMsg::UpdateConfig(msg) => update_config(deps, env, info, msg),
+        ExecuteMsg::ExecuteConfigChange { id } => execute_config_change(deps, env, info, id),
+        ExecuteMsg::CancelConfigChange { id } => cancel_config_change(deps, info, id),
+        ExecuteMsg::GrantRole { address, role } => grant_role(deps, info, address, role),
+        ExecuteMsg::RevokeRole { address, role } => revoke_role(deps, info, address, role),
+        ExecuteMsg::ProposeAdmin { new_admin, expires } => {
+            propose_admin(deps, env, info, new_admin, expires)
+        }
+        ExecuteMsg::AcceptAdmin {} => accept_admin(deps, env, info),
+        ExecuteMsg::CancelAdminProposal {} => cancel_admin_proposal(deps, info),
+        ExecuteMsg::Pause {
+            deposits,
+            withdrawals,
+        } => pause(deps, info, deposits, withdrawals),
+        ExecuteMsg::SetEmergencyMode { enabled } => set_emergency_mode(deps, info, enabled),
+        ExecuteMsg::DepositPool {
+            percentage,
+            lock_until,
+        } => deposit_pool(deps, env, info, percentage, lock_until),
         ExecuteMsg::WithdrawPool { withdraw_amount } => withdraw_pool(deps, env, info, withdraw_amount),
+        ExecuteMsg::RequestWithdraw { withdraw_amount } => {
+            request_withdraw(deps, env, info, withdraw_amount)
+        }
+        ExecuteMsg::ClaimWithdraw {} => claim_withdraw(deps, env, info),
+        ExecuteMsg::ExecuteClaim {} => execute_claim(deps, env, info),
+        ExecuteMsg::EmergencyWithdraw {} => emergency_withdraw(deps, env, info),
+        ExecuteMsg::SetDonationMode { mode } => set_donation_mode(deps, env, info, mode),
+        ExecuteMsg::SetDonationGoal { goal } => set_donation_goal(deps, env, info, goal),
+        ExecuteMsg::SetGiveSchedule { schedule } => set_give_schedule(deps, env, info, schedule),
+        ExecuteMsg::ResolveDiscrepancy { destination } => {
+            resolve_discrepancy(deps, env, info, destination)
+        }
+        ExecuteMsg::RecoverTokens {
+            asset,
+            amount,
+            recipient,
+        } => recover_tokens(deps, env, info, asset, amount, recipient),
         /* Three Entry Points */
         
         /* Internal Contract Calls */
         ExecuteMsg::InternalDepositInitial {
             ust_sent,
             percentage,
+            lock_until,
             depositor,
-        } => deposit_initial(deps, env, info, ust_sent, percentage, depositor),
+        } => deposit_initial(deps, env, info, ust_sent, percentage, lock_until, depositor),
         ExecuteMsg::InternalDepositMore {
             ust_sent,
             aust_amount,
             percentage,
             depositor,
         } => deposit_more(deps, env, info, ust_sent, aust_amount, percentage, depositor),
-        ExecuteMsg::InternalSwapBackUpdate {
-            to_angel,
-            charity_address,
-            ust_amount,
-            new_percentage,
-            depositor,
-        } => swap_back_aust(
-            deps,
-            env,
-            info,
-            to_angel,
-            charity_address,
-            ust_amount,
-            new_percentage,
-            depositor,
-        ),
+        ExecuteMsg::InternalSwapBackUpdate(msg) => swap_back_aust(deps, env, info, msg),
         ExecuteMsg::InternalWithdrawInitial {
             withdraw_amount,
             aust_amount,
@@ -115,25 +203,806 @@ pub fn execute(
             percentage,
             depositor,
         ),
-        ExecuteMsg::InternalWithdrawSend {
-            withdraw_amount,
-            new_ust_amount,
-            to_angel_amount,
-            ust_depositor,
-            charity_address,
-        } => withdraw_send(
-            deps,
-            env,
-            info,
-            withdraw_amount,
-            new_ust_amount,
-            to_angel_amount,
-            ust_depositor,
-            charity_address,
-        ),
+        ExecuteMsg::InternalEmergencyRedeem {
+            aust_amount,
+            ust_amount,
+            percentage,
+            depositor,
+        } => emergency_redeem(deps, env, info, aust_amount, ust_amount, percentage, depositor),
+        ExecuteMsg::InternalWithdrawSend(msg) => withdraw_send(deps, env, info, msg),
         /* Internal Contract Calls */
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+    use crate::msg::UpdateConfigMsg;
+    use crate::state::{FeeTarget, Role};
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info, MockApi, MockQuerier};
+    use crate::msg::RecoverAsset;
+    use crate::state::RECOVERIES;
+    use crate::msg::WithdrawSendMsg;
+    use cw20::{Cw20ExecuteMsg, Expiration};
+    use crate::msg::{QueryMsg, ReconcileResponse, SurplusDestination};
+    use crate::query::query;
+    use crate::testing::{mock_dependencies_with_anchor, mock_dependencies_with_tax, AnchorQuerier};
+    use crate::helpers::save_position;
+    use crate::state::{user_pools, Pool, PENDING_CLAIMS, PENDING_WITHDRAWS};
+    use crate::tax::TaxInfo;
+    use cosmwasm_std::{
+        from_binary, to_binary, ContractResult, Deps, Event, SubMsgExecutionResponse, Uint128,
+        WasmMsg,
+    };
+    use cosmwasm_std::{coin, Addr, BankMsg, Coin, CosmosMsg, Decimal, Env, MemoryStorage, OwnedDeps};
+    use cw_storage_plus::U64Key;
+
+    const TIMELOCK: u64 = 3_600;
+
+    fn setup() -> OwnedDeps<MemoryStorage, MockApi, MockQuerier> {
+        setup_with_balance(&[])
+    }
+
+    fn setup_with_balance(balance: &[Coin]) -> OwnedDeps<MemoryStorage, MockApi, MockQuerier> {
+        let mut deps = mock_dependencies(balance);
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), instantiate_msg()).unwrap();
+        deps
+    }
+
+    fn instantiate_msg() -> InstantiateMsg {
+        InstantiateMsg {
+            admin: Addr::unchecked("admin"),
+            charity_address: Addr::unchecked("charity"),
+            anchor_market_address: Addr::unchecked("anchor"),
+            aust_token_address: Addr::unchecked("aust"),
+            theta: 1_000,
+            timelock_delay: TIMELOCK,
+            min_deposit: 1_000,
+            min_percentage: 5,
+            max_percentage: 100,
+            max_deposit_per_user: None,
+            max_total_deposits: None,
+            protocol_fee_bps: 0,
+            fee_collector: None,
+            fee_target: FeeTarget::Donation,
+            early_exit_penalty_bps: None,
+            withdraw_cooldown: 0,
+        }
+    }
+
+    fn later(seconds: u64) -> Env {
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(seconds);
+        env
+    }
+
+    fn grant(deps: DepsMut, address: &str, role: Role) {
+        let msg = ExecuteMsg::GrantRole {
+            address: address.to_string(),
+            role,
+        };
+        execute(deps, mock_env(), mock_info("admin", &[]), msg).unwrap();
+    }
+
+    fn queue_charity_change(deps: DepsMut, sender: &str) {
+        let msg = ExecuteMsg::UpdateConfig(UpdateConfigMsg {
+            charity_address: Some("new_charity".to_string()),
+            ..UpdateConfigMsg::default()
+        });
+        execute(deps, mock_env(), mock_info(sender, &[]), msg).unwrap();
+    }
+
+    #[test]
+    fn sensitive_change_waits_for_timelock() {
+        let mut deps = setup();
+        queue_charity_change(deps.as_mut(), "admin");
+        assert_eq!(CONFIG.load(&deps.storage).unwrap().charity_address, Addr::unchecked("charity"));
+
+        let msg = ExecuteMsg::ExecuteConfigChange { id: 0 };
+        let err = execute(deps.as_mut(), later(TIMELOCK - 1), mock_info("admin", &[]), msg.clone());
+        assert!(matches!(err, Err(ContractError::TimelockNotExpired { id: 0, .. })));
+
+        execute(deps.as_mut(), later(TIMELOCK), mock_info("admin", &[]), msg).unwrap();
+        let config = CONFIG.load(&deps.storage).unwrap();
+        assert_eq!(config.charity_address, Addr::unchecked("new_charity"));
+    }
+
+    #[test]
+    fn cancelled_change_cannot_be_applied() {
+        let mut deps = setup();
+        queue_charity_change(deps.as_mut(), "admin");
+
+        let msg = ExecuteMsg::CancelConfigChange { id: 0 };
+        execute(deps.as_mut(), mock_env(), mock_info("admin", &[]), msg).unwrap();
+
+        let msg = ExecuteMsg::ExecuteConfigChange { id: 0 };
+        let err = execute(deps.as_mut(), later(TIMELOCK), mock_info("admin", &[]), msg);
+        assert!(err.is_err());
+        assert_eq!(CONFIG.load(&deps.storage).unwrap().charity_address, Addr::unchecked("charity"));
+    }
+
+    #[test]
+    fn charity_manager_applies_recipient_change() {
+        let mut deps = setup();
+        grant(deps.as_mut(), "manager", Role::CharityManager);
+        queue_charity_change(deps.as_mut(), "manager");
+
+        let msg = ExecuteMsg::ExecuteConfigChange { id: 0 };
+        execute(deps.as_mut(), later(TIMELOCK), mock_info("manager", &[]), msg).unwrap();
+
+        let config = CONFIG.load(&deps.storage).unwrap();
+        assert_eq!(config.charity_address, Addr::unchecked("new_charity"));
+    }
+
+    #[test]
+    fn charity_manager_cannot_apply_other_changes() {
+        let mut deps = setup();
+        grant(deps.as_mut(), "manager", Role::CharityManager);
+        let msg = ExecuteMsg::UpdateConfig(UpdateConfigMsg {
+            anchor_market_address: Some("new_anchor".to_string()),
+            ..UpdateConfigMsg::default()
+        });
+        execute(deps.as_mut(), mock_env(), mock_info("admin", &[]), msg).unwrap();
+
+        let msg = ExecuteMsg::ExecuteConfigChange { id: 0 };
+        let err = execute(deps.as_mut(), later(TIMELOCK), mock_info("manager", &[]), msg);
+        assert!(matches!(err, Err(ContractError::Unauthorized {})));
+    }
+
+    #[test]
+    fn partial_config_update_changes_only_given_fields() {
+        let mut deps = setup();
+        let before = CONFIG.load(&deps.storage).unwrap();
+        let msg = ExecuteMsg::UpdateConfig(UpdateConfigMsg {
+            min_deposit: Some(5_000),
+            max_percentage: Some(50),
+            ..UpdateConfigMsg::default()
+        });
+        execute(deps.as_mut(), mock_env(), mock_info("admin", &[]), msg).unwrap();
+        let after = CONFIG.load(&deps.storage).unwrap();
+        assert_eq!(
+            after,
+            Config {
+                min_deposit: 5_000,
+                max_percentage: 50,
+                ..before
+            }
+        );
+
+        // bounds are checked against the stored values they are combined with
+        let msg = ExecuteMsg::UpdateConfig(UpdateConfigMsg {
+            min_percentage: Some(60),
+            ..UpdateConfigMsg::default()
+        });
+        let err = execute(deps.as_mut(), mock_env(), mock_info("admin", &[]), msg);
+        assert!(matches!(err, Err(ContractError::InvalidPercentageBounds {})));
+
+        let msg = ExecuteMsg::UpdateConfig(UpdateConfigMsg {
+            theta: Some(MAX_THETA + 1),
+            ..UpdateConfigMsg::default()
+        });
+        let err = execute(deps.as_mut(), mock_env(), mock_info("admin", &[]), msg);
+        assert!(matches!(err, Err(ContractError::InvalidTheta { .. })));
+        assert_eq!(CONFIG.load(&deps.storage).unwrap(), after);
+    }
+
+    #[test]
+    fn empty_config_update_is_rejected() {
+        let mut deps = setup();
+        let msg = ExecuteMsg::UpdateConfig(UpdateConfigMsg::default());
+        let err = execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), msg);
+        assert!(matches!(err, Err(ContractError::EmptyConfigUpdate {})));
+    }
+
+    #[test]
+    fn roles_gate_config_fields() {
+        let mut deps = setup();
+        grant(deps.as_mut(), "operator", Role::Operator);
+
+        // operators tune theta but cannot touch the recipient
+        let msg = ExecuteMsg::UpdateConfig(UpdateConfigMsg {
+            theta: Some(500),
+            ..UpdateConfigMsg::default()
+        });
+        execute(deps.as_mut(), mock_env(), mock_info("operator", &[]), msg).unwrap();
+        assert_eq!(CONFIG.load(&deps.storage).unwrap().theta, 500);
+
+        let msg = ExecuteMsg::UpdateConfig(UpdateConfigMsg {
+            charity_address: Some("new_charity".to_string()),
+            ..UpdateConfigMsg::default()
+        });
+        let err = execute(deps.as_mut(), mock_env(), mock_info("operator", &[]), msg);
+        assert!(matches!(err, Err(ContractError::Unauthorized {})));
+
+        let msg = ExecuteMsg::UpdateConfig(UpdateConfigMsg {
+            theta: Some(400),
+            ..UpdateConfigMsg::default()
+        });
+        let err = execute(deps.as_mut(), mock_env(), mock_info("stranger", &[]), msg);
+        assert!(matches!(err, Err(ContractError::Unauthorized {})));
+    }
+
+    #[test]
+    fn recovering_untaxed_denom_records_full_amount() {
+        // no Terra tax query is made, the plain mock querier would reject it
+        let mut deps = setup_with_balance(&[coin(500, "uluna")]);
+        let msg = ExecuteMsg::RecoverTokens {
+            asset: RecoverAsset::Native {
+                denom: "uluna".to_string(),
+            },
+            amount: 500u128.into(),
+            recipient: "treasury".to_string(),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("admin", &[]), msg).unwrap();
+
+        let recovery = RECOVERIES.load(&deps.storage, U64Key::new(0)).unwrap();
+        assert_eq!(recovery.amount.u128(), 500);
+        assert_eq!(recovery.asset, "uluna");
+    }
+
+    #[test]
+    fn withdraw_send_skips_empty_user_transfer() {
+        let mut deps = mock_dependencies_with_tax(Decimal::permille(5), 1_400_000, &[]);
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), instantiate_msg()).unwrap();
+
+        let env = mock_env();
+        let msg = ExecuteMsg::InternalWithdrawSend(WithdrawSendMsg {
+            withdraw_amount: 0,
+            new_ust_amount: 0,
+            to_angel_amount: 1_005_000,
+            protocol_fee: 0,
+            ust_depositor: Addr::unchecked("depositor"),
+            charity_address: Addr::unchecked("charity"),
+        });
+        let info = mock_info(env.contract.address.as_str(), &[]);
+        let res = execute(deps.as_mut(), env, info, msg).unwrap();
+
+        assert_eq!(res.messages.len(), 1);
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "charity".to_string(),
+                amount: vec![coin(1_000_000, "uusd")],
+            })
+        );
+    }
+
+    const COOLDOWN: u64 = 600;
+
+    // one aUST is worth 1.1 UST and the market has nothing to redeem with,
+    // so withdrawals queue as claims without leaving the contract
+    fn setup_with_position(aust_amount: u64) -> OwnedDeps<MemoryStorage, MockApi, AnchorQuerier> {
+        let mut deps = mock_dependencies_with_anchor(Decimal::percent(110), &[]);
+        let msg = InstantiateMsg {
+            withdraw_cooldown: COOLDOWN,
+            ..instantiate_msg()
+        };
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let position = Pool {
+            give_percentage: 10,
+            ust_amount: aust_amount,
+            aust_amount,
+            ..Pool::default()
+        };
+        save_position(&mut deps.storage, 0, "depositor", &position).unwrap();
+        deps
+    }
+
+    fn request_withdraw(deps: DepsMut, amount: u128) {
+        let msg = ExecuteMsg::RequestWithdraw {
+            withdraw_amount: Uint128::new(amount),
+        };
+        execute(deps, mock_env(), mock_info("depositor", &[]), msg).unwrap();
+    }
+
+    #[test]
+    fn deposit_cancels_requested_withdrawal() {
+        let mut deps = setup_with_position(1_000_000);
+        request_withdraw(deps.as_mut(), 500_000);
+
+        let msg = ExecuteMsg::DepositPool {
+            percentage: 10,
+            lock_until: None,
+        };
+        let info = mock_info("depositor", &[coin(5_000_000, "uusd")]);
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        assert!(PENDING_WITHDRAWS.may_load(&deps.storage, "depositor").unwrap().is_none());
+
+        let err = execute(
+            deps.as_mut(),
+            later(COOLDOWN),
+            mock_info("depositor", &[]),
+            ExecuteMsg::ClaimWithdraw {},
+        );
+        assert!(matches!(err, Err(ContractError::NoPendingWithdraw {})));
+    }
+
+    #[test]
+    fn claim_is_capped_at_position_value_when_requested() {
+        let mut deps = setup_with_position(1_000_000);
+        request_withdraw(deps.as_mut(), 5_000_000);
+
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("depositor", &[]),
+            ExecuteMsg::ClaimWithdraw {},
+        );
+        let unlock_time = mock_env().block.time.seconds() + COOLDOWN;
+        assert!(matches!(err, Err(ContractError::WithdrawNotReady { unlock_time: t }) if t == unlock_time));
+
+        execute(
+            deps.as_mut(),
+            later(COOLDOWN),
+            mock_info("depositor", &[]),
+            ExecuteMsg::ClaimWithdraw {},
+        )
+        .unwrap();
+        assert_eq!(
+            PENDING_CLAIMS.load(&deps.storage, "depositor").unwrap(),
+            Uint128::new(1_100_000)
+        );
+    }
+
+    // uusd leaving the contract for `res`, each transfer plus its tax
+    fn paid_out(tax: &TaxInfo, res: &Response) -> u64 {
+        res.messages
+            .iter()
+            .flat_map(|sub| match &sub.msg {
+                CosmosMsg::Bank(BankMsg::Send { amount, .. }) => amount.clone(),
+                CosmosMsg::Wasm(WasmMsg::Execute { funds, .. }) => funds.clone(),
+                _ => vec![],
+            })
+            .map(|coin| {
+                let sent = coin.amount;
+                (sent + (sent * tax.rate).min(tax.cap)).u128() as u64
+            })
+            .sum()
+    }
+
+    #[test]
+    fn withdrawal_pays_out_no_more_than_anchor_sent() {
+        let tax = TaxInfo {
+            rate: Decimal::permille(5),
+            cap: Uint128::new(1_400_000),
+        };
+        let mut deps = mock_dependencies_with_tax(tax.rate, tax.cap.u128(), &[]);
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), instantiate_msg()).unwrap();
+        let position = Pool {
+            give_percentage: 50,
+            ust_amount: 1_000_000,
+            aust_amount: 1_000_000,
+            high_water_mark: 1_000_000,
+            ..Pool::default()
+        };
+        save_position(&mut deps.storage, 0, "depositor", &position).unwrap();
+
+        // Anchor reports the gross redemption but sends it net of tax
+        let redeemed = Event::new("wasm")
+            .add_attribute("redeem_amount", "1105000")
+            .add_attribute("withdraw_amount", "2000000")
+            .add_attribute("ust_depositor", "depositor")
+            .add_attribute("ust_amount", "1000000")
+            .add_attribute("aust_amount", "1000000");
+        let msg = Reply {
+            id: 3,
+            result: ContractResult::Ok(SubMsgExecutionResponse {
+                events: vec![redeemed],
+                data: None,
+            }),
+        };
+        let res = reply(deps.as_mut(), mock_env(), msg).unwrap();
+        let send = match &res.messages[0].msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { msg, .. }) => from_binary(msg).unwrap(),
+            _ => panic!("expected the internal withdraw send"),
+        };
+
+        let env = mock_env();
+        let info = mock_info(env.contract.address.as_str(), &[]);
+        let res = execute(deps.as_mut(), env, info, send).unwrap();
+        assert!(paid_out(&tax, &res) <= tax.deduct_tax(1_105_000));
+    }
+
+    #[test]
+    fn raising_a_reached_goal_restores_the_percentage() {
+        let mut deps = setup();
+        let position = Pool {
+            ust_amount: 1_000_000,
+            aust_amount: 1_000_000,
+            total_donated: 50_000,
+            donation_goal: Some(50_000),
+            paused_percentage: Some(40),
+            ..Pool::default()
+        };
+        save_position(&mut deps.storage, 0, "depositor", &position).unwrap();
+
+        let msg = ExecuteMsg::SetDonationGoal {
+            goal: Some(100_000),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("depositor", &[]), msg).unwrap();
+        let position = user_pools().load(&deps.storage, "depositor").unwrap();
+        assert_eq!(position.give_percentage, 40);
+        assert_eq!(position.paused_percentage, None);
+    }
+
+    #[test]
+    fn deposit_cancels_queued_claim() {
+        let mut deps = setup_with_position(1_000_000);
+        request_withdraw(deps.as_mut(), 500_000);
+        let info = mock_info("depositor", &[]);
+        execute(deps.as_mut(), later(COOLDOWN), info, ExecuteMsg::ClaimWithdraw {}).unwrap();
+        assert!(PENDING_CLAIMS.may_load(&deps.storage, "depositor").unwrap().is_some());
+
+        let msg = ExecuteMsg::DepositPool {
+            percentage: 10,
+            lock_until: None,
+        };
+        let info = mock_info("depositor", &[coin(5_000_000, "uusd")]);
+        execute(deps.as_mut(), later(COOLDOWN), info, msg).unwrap();
+
+        let err = execute(
+            deps.as_mut(),
+            later(COOLDOWN),
+            mock_info("depositor", &[]),
+            ExecuteMsg::ExecuteClaim {},
+        );
+        assert!(matches!(err, Err(ContractError::NoPendingClaim {})));
+    }
+
+    #[test]
+    fn queued_claim_is_replaced_and_capped() {
+        let mut deps = setup_with_position(1_000_000);
+        for (requested, queued) in [(5_000_000, 1_100_000), (300_000, 300_000)] {
+            request_withdraw(deps.as_mut(), requested);
+            let info = mock_info("depositor", &[]);
+            execute(deps.as_mut(), later(COOLDOWN), info, ExecuteMsg::ClaimWithdraw {}).unwrap();
+            assert_eq!(
+                PENDING_CLAIMS.load(&deps.storage, "depositor").unwrap(),
+                Uint128::new(queued)
+            );
+        }
+    }
+
+    #[test]
+    fn emergency_withdraw_redeems_only_available_liquidity() {
+        let mut deps = setup_with_position(1_000_000);
+        let msg = ExecuteMsg::SetEmergencyMode { enabled: true };
+        execute(deps.as_mut(), mock_env(), mock_info("admin", &[]), msg).unwrap();
+
+        let info = mock_info("depositor", &[]);
+        let err = execute(deps.as_mut(), mock_env(), info.clone(), ExecuteMsg::EmergencyWithdraw {});
+        assert!(matches!(err, Err(ContractError::NoLiquidity {})));
+
+        // 0.55 UST of liquidity redeems half the position at 1.1
+        let liquidity = [coin(550_000, "uusd")];
+        deps.querier = mock_dependencies_with_anchor(Decimal::percent(110), &liquidity).querier;
+        let res = execute(deps.as_mut(), mock_env(), info, ExecuteMsg::EmergencyWithdraw {}).unwrap();
+        let redeem = match &res.messages[0].msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { msg, .. }) => from_binary(msg).unwrap(),
+            _ => panic!("expected the internal emergency redeem"),
+        };
+        match redeem {
+            ExecuteMsg::InternalEmergencyRedeem { aust_amount, ust_amount, .. } => {
+                assert_eq!((aust_amount, ust_amount), (500_000, 500_000))
+            }
+            _ => panic!("expected the internal emergency redeem"),
+        }
+
+        let redeemed = Event::new("wasm")
+            .add_attribute("redeem_amount", "550000")
+            .add_attribute("ust_amount", "500000")
+            .add_attribute("aust_amount", "500000")
+            .add_attribute("ust_depositor", "depositor");
+        let msg = Reply {
+            id: 5,
+            result: ContractResult::Ok(SubMsgExecutionResponse {
+                events: vec![redeemed],
+                data: None,
+            }),
+        };
+        reply(deps.as_mut(), mock_env(), msg).unwrap();
+        let position = user_pools().load(&deps.storage, "depositor").unwrap();
+        assert_eq!((position.aust_amount, position.ust_amount), (500_000, 500_000));
+        assert_eq!(position.give_percentage, 10);
+    }
+
+    #[test]
+    fn top_up_moves_the_mark_by_what_reaches_anchor() {
+        let tax = TaxInfo {
+            rate: Decimal::permille(5),
+            cap: Uint128::new(1_400_000),
+        };
+        let mut deps = mock_dependencies_with_tax(tax.rate, tax.cap.u128(), &[]);
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), instantiate_msg()).unwrap();
+        let position = Pool {
+            give_percentage: 10,
+            ust_amount: 1_000_000,
+            aust_amount: 1_000_000,
+            high_water_mark: 1_000_000,
+            ..Pool::default()
+        };
+        save_position(&mut deps.storage, 0, "depositor", &position).unwrap();
+
+        // no yield: Anchor's 1.005 UST gross is exactly the principal after tax
+        let redeemed = Event::new("wasm")
+            .add_attribute("ust_sent", "1000000")
+            .add_attribute("redeem_amount", "1005000")
+            .add_attribute("percentage", "10")
+            .add_attribute("ust_depositor", "depositor");
+        let msg = Reply {
+            id: 1,
+            result: ContractResult::Ok(SubMsgExecutionResponse {
+                events: vec![redeemed],
+                data: None,
+            }),
+        };
+        reply(deps.as_mut(), mock_env(), msg).unwrap();
+        let position = user_pools().load(&deps.storage, "depositor").unwrap();
+        assert_eq!(position.high_water_mark, tax.deduct_tax(2_000_000));
+    }
+
+    #[test]
+    fn admin_transfer_needs_acceptance() {
+        let mut deps = setup();
+        let propose = |expires| ExecuteMsg::ProposeAdmin {
+            new_admin: "next".to_string(),
+            expires,
+        };
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("next", &[]), propose(None));
+        assert!(matches!(err, Err(ContractError::Unauthorized {})));
+        execute(deps.as_mut(), mock_env(), mock_info("admin", &[]), propose(None)).unwrap();
+        assert_eq!(CONFIG.load(&deps.storage).unwrap().admin, "admin");
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("other", &[]), ExecuteMsg::AcceptAdmin {});
+        assert!(matches!(err, Err(ContractError::Unauthorized {})));
+        execute(deps.as_mut(), mock_env(), mock_info("next", &[]), ExecuteMsg::AcceptAdmin {}).unwrap();
+        assert_eq!(CONFIG.load(&deps.storage).unwrap().admin, "next");
+
+        // an expired proposal can't be accepted, a cancelled one is gone
+        let expires = Expiration::AtTime(later(60).block.time);
+        execute(deps.as_mut(), mock_env(), mock_info("next", &[]), propose(Some(expires))).unwrap();
+        let err = execute(deps.as_mut(), later(60), mock_info("next", &[]), ExecuteMsg::AcceptAdmin {});
+        assert!(matches!(err, Err(ContractError::AdminProposalExpired {})));
+        let cancel = ExecuteMsg::CancelAdminProposal {};
+        execute(deps.as_mut(), mock_env(), mock_info("next", &[]), cancel).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info("next", &[]), ExecuteMsg::AcceptAdmin {});
+        assert!(matches!(err, Err(ContractError::NoPendingAdmin {})));
+    }
+
+    #[test]
+    fn pause_and_emergency_mode_gate_user_actions() {
+        let mut deps = setup();
+        grant(deps.as_mut(), "pauser", Role::Pauser);
+        let deposit = || ExecuteMsg::DepositPool {
+            percentage: 10,
+            lock_until: None,
+        };
+        let withdraw = || ExecuteMsg::WithdrawPool {
+            withdraw_amount: Uint128::new(1_000),
+        };
+        let funds = [coin(5_000, "uusd")];
+
+        // a pauser can only stop deposits
+        let msg = ExecuteMsg::Pause { deposits: true, withdrawals: true };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("pauser", &[]), msg);
+        assert!(matches!(err, Err(ContractError::Unauthorized {})));
+        let msg = ExecuteMsg::Pause { deposits: true, withdrawals: false };
+        execute(deps.as_mut(), mock_env(), mock_info("pauser", &[]), msg).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info("depositor", &funds), deposit());
+        assert!(matches!(err, Err(ContractError::DepositsPaused {})));
+
+        let msg = ExecuteMsg::Pause { deposits: false, withdrawals: true };
+        execute(deps.as_mut(), mock_env(), mock_info("admin", &[]), msg).unwrap();
+        execute(deps.as_mut(), mock_env(), mock_info("depositor", &funds), deposit()).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info("depositor", &[]), withdraw());
+        assert!(matches!(err, Err(ContractError::WithdrawalsPaused {})));
+
+        // emergency withdrawals only exist in emergency mode, which stops deposits
+        let emergency = ExecuteMsg::EmergencyWithdraw {};
+        let err = execute(deps.as_mut(), mock_env(), mock_info("depositor", &[]), emergency);
+        assert!(matches!(err, Err(ContractError::NotInEmergency {})));
+        let msg = ExecuteMsg::SetEmergencyMode { enabled: true };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("pauser", &[]), msg.clone());
+        assert!(matches!(err, Err(ContractError::Unauthorized {})));
+        execute(deps.as_mut(), mock_env(), mock_info("admin", &[]), msg).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info("depositor", &funds), deposit());
+        assert!(matches!(err, Err(ContractError::DepositsPaused {})));
+    }
+
+    #[test]
+    fn deposits_are_held_to_the_configured_limits() {
+        let mut deps = mock_dependencies(&[]);
+        let msg = InstantiateMsg {
+            max_deposit_per_user: Some(10_000),
+            max_total_deposits: Some(15_000),
+            ..instantiate_msg()
+        };
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        for (depositor, ust_amount) in [("depositor", 8_000), ("whale", 5_000)] {
+            let position = Pool {
+                give_percentage: 10,
+                ust_amount,
+                aust_amount: ust_amount,
+                ..Pool::default()
+            };
+            save_position(&mut deps.storage, 0, depositor, &position).unwrap();
+        }
+        let deposit = |percentage| ExecuteMsg::DepositPool {
+            percentage,
+            lock_until: None,
+        };
+
+        for percentage in [4, 101] {
+            let info = mock_info("newcomer", &[coin(2_000, "uusd")]);
+            let err = execute(deps.as_mut(), mock_env(), info, deposit(percentage));
+            assert!(matches!(
+                err,
+                Err(ContractError::WrongPercentageInput { min: 5, max: 100 })
+            ));
+        }
+        let info = mock_info("newcomer", &[coin(999, "uusd")]);
+        let err = execute(deps.as_mut(), mock_env(), info, deposit(10));
+        assert!(matches!(err, Err(ContractError::DepositTooSmall { min: 1_000 })));
+
+        // 8_000 + 3_000 is over the depositor's own cap
+        let info = mock_info("depositor", &[coin(3_000, "uusd")]);
+        let err = execute(deps.as_mut(), mock_env(), info, deposit(10));
+        assert!(matches!(err, Err(ContractError::UserCapExceeded { cap: 10_000 })));
+        // 13_000 + 3_000 is over the contract-wide cap
+        let info = mock_info("newcomer", &[coin(3_000, "uusd")]);
+        let err = execute(deps.as_mut(), mock_env(), info, deposit(10));
+        assert!(matches!(err, Err(ContractError::TotalCapExceeded { cap: 15_000 })));
+
+        let info = mock_info("newcomer", &[coin(2_000, "uusd")]);
+        execute(deps.as_mut(), mock_env(), info, deposit(10)).unwrap();
+    }
+
+    #[test]
+    fn protocol_fee_goes_to_the_collector_net_of_tax() {
+        let fee_send = |deps: DepsMut| {
+            let env = mock_env();
+            let msg = ExecuteMsg::InternalWithdrawSend(WithdrawSendMsg {
+                withdraw_amount: 0,
+                new_ust_amount: 0,
+                to_angel_amount: 0,
+                protocol_fee: 201_000,
+                ust_depositor: Addr::unchecked("depositor"),
+                charity_address: Addr::unchecked("charity"),
+            });
+            let info = mock_info(env.contract.address.as_str(), &[]);
+            execute(deps, env, info, msg).unwrap()
+        };
+
+        let mut deps = mock_dependencies_with_tax(Decimal::permille(5), 1_400_000, &[]);
+        let msg = InstantiateMsg {
+            protocol_fee_bps: 100,
+            fee_collector: Some(Addr::unchecked("collector")),
+            ..instantiate_msg()
+        };
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        let res = fee_send(deps.as_mut());
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "collector".to_string(),
+                amount: vec![coin(200_000, "uusd")],
+            })
+        );
+        assert!(res.attributes.iter().any(|a| a.key == "protocol_fee" && a.value == "200000"));
+        assert_eq!(TOTALS.load(&deps.storage).unwrap().total_fees, 200_000);
+
+        // without a collector there is nowhere to send it
+        let mut deps = mock_dependencies_with_tax(Decimal::permille(5), 1_400_000, &[]);
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), instantiate_msg()).unwrap();
+        let res = fee_send(deps.as_mut());
+        assert!(res.messages.is_empty());
+        assert!(res.attributes.iter().any(|a| a.key == "protocol_fee" && a.value == "0"));
+        assert_eq!(TOTALS.load(&deps.storage).unwrap().total_fees, 0);
+    }
+
+    #[test]
+    fn surplus_aust_is_reported_and_only_surplus_is_swept() {
+        let mut deps = mock_dependencies_with_anchor(Decimal::one(), &[]);
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), instantiate_msg()).unwrap();
+        let position = Pool {
+            give_percentage: 10,
+            ust_amount: 1_000,
+            aust_amount: 1_000,
+            ..Pool::default()
+        };
+        save_position(&mut deps.storage, 0, "depositor", &position).unwrap();
+        let reconcile = |deps: Deps| -> ReconcileResponse {
+            from_binary(&query(deps, mock_env(), QueryMsg::Reconcile {}).unwrap()).unwrap()
+        };
+        let resolve = || ExecuteMsg::ResolveDiscrepancy {
+            destination: SurplusDestination::Reserve {
+                address: "reserve".to_string(),
+            },
+        };
+
+        // someone sent the contract 250 aUST outside of any deposit
+        deps.querier.set_aust_balance(1_250);
+        let report = reconcile(deps.as_ref());
+        assert_eq!((report.ledger_aust, report.surplus, report.deficit), (1_000, 250, 0));
+
+        let err = execute(deps.as_mut(), mock_env(), mock_info("depositor", &[]), resolve());
+        assert!(matches!(err, Err(ContractError::Unauthorized {})));
+        let res = execute(deps.as_mut(), mock_env(), mock_info("admin", &[]), resolve()).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: "aust".to_string(),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: "reserve".to_string(),
+                    amount: Uint128::new(250),
+                })
+                .unwrap(),
+                funds: vec![],
+            })
+        );
+
+        // a shortfall is reported but there is nothing to sweep
+        deps.querier.set_aust_balance(900);
+        let report = reconcile(deps.as_ref());
+        assert_eq!((report.surplus, report.deficit), (0, 100));
+        let err = execute(deps.as_mut(), mock_env(), mock_info("admin", &[]), resolve());
+        assert!(matches!(err, Err(ContractError::NoSurplus {})));
+    }
+
+    #[test]
+    fn leaving_a_lock_early_gives_the_penalty_to_charity() {
+        let locked_position = |deps: DepsMut| {
+            let position = Pool {
+                give_percentage: 10,
+                ust_amount: 1_000_000,
+                aust_amount: 1_000_000,
+                high_water_mark: 1_000_000,
+                lock_until: Some(mock_env().block.time.seconds() + 1_000),
+                ..Pool::default()
+            };
+            save_position(deps.storage, 0, "depositor", &position).unwrap();
+        };
+        // the whole position comes back with no yield, so only the penalty is donated
+        let redeemed = || Reply {
+            id: 3,
+            result: ContractResult::Ok(SubMsgExecutionResponse {
+                events: vec![Event::new("wasm")
+                    .add_attribute("redeem_amount", "1000000")
+                    .add_attribute("withdraw_amount", "1000000")
+                    .add_attribute("ust_depositor", "depositor")
+                    .add_attribute("ust_amount", "1000000")
+                    .add_attribute("aust_amount", "1000000")],
+                data: None,
+            }),
+        };
+
+        let mut deps = mock_dependencies_with_tax(Decimal::zero(), 0, &[]);
+        let msg = InstantiateMsg {
+            early_exit_penalty_bps: Some(500),
+            ..instantiate_msg()
+        };
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        locked_position(deps.as_mut());
+        let res = reply(deps.as_mut(), mock_env(), redeemed()).unwrap();
+        assert!(res.attributes.iter().any(|a| a.key == "early_exit_penalty" && a.value == "50000"));
+        let send: ExecuteMsg = match &res.messages[0].msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { msg, .. }) => from_binary(msg).unwrap(),
+            _ => panic!("expected the internal withdraw send"),
+        };
+        match send {
+            ExecuteMsg::InternalWithdrawSend(send) => {
+                assert_eq!(send.withdraw_amount, 950_000);
+                assert_eq!(send.to_angel_amount, 50_000);
+            }
+            _ => panic!("expected the internal withdraw send"),
+        }
+
+        // without a penalty the lock simply holds
+        let mut deps = mock_dependencies_with_tax(Decimal::zero(), 0, &[]);
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), instantiate_msg()).unwrap();
+        locked_position(deps.as_mut());
+        let err = reply(deps.as_mut(), mock_env(), redeemed());
+        assert!(matches!(err, Err(ContractError::PositionLocked { .. })));
+    }
+}
@@ -1,4 +1,4 @@
-use cosmwasm_std::StdError;
+use cosmwasm_std::{StdError, Uint128};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -9,15 +9,96 @@ pub enum ContractError {
     #[error("Unauthorized")]
     Unauthorized {},
 
-    #[error("Something went wrong in making new pool")]
-    MakeNewPoolError {},
+    #[error("Percentage needs to be between {min}% and {max}%")]
+    WrongPercentageInput { min: u16, max: u16 },
 
-    #[error("Percentage needs to be between 5% and 100%")]
-    WrongPercentageInput {},
+    #[error("Percentage bounds must satisfy min <= max <= 100")]
+    InvalidPercentageBounds {},
+
+    #[error("Config update sets no fields")]
+    EmptyConfigUpdate {},
+
+    #[error("Deposit must be at least {min} uusd")]
+    DepositTooSmall { min: u64 },
+
+    #[error("Deposit would exceed the per-user cap of {cap} uusd")]
+    UserCapExceeded { cap: u64 },
+
+    #[error("Deposit would exceed the total deposit cap of {cap} uusd")]
+    TotalCapExceeded { cap: u64 },
+
+    #[error("No admin proposal is pending")]
+    NoPendingAdmin {},
+
+    #[error("Admin proposal has expired")]
+    AdminProposalExpired {},
+
+    #[error("Config change {id} is timelocked until {eta}")]
+    TimelockNotExpired { id: u64, eta: u64 },
+
+    #[error("Protocol fee must not exceed {max} bps")]
+    InvalidProtocolFee { max: u16 },
+
+    #[error("Early exit penalty must not exceed {max} bps")]
+    InvalidEarlyExitPenalty { max: u16 },
+
+    #[error("Theta must not exceed {max}")]
+    InvalidTheta { max: u64 },
+
+    #[error("Deposits are paused")]
+    DepositsPaused {},
+
+    #[error("Withdrawals are paused")]
+    WithdrawalsPaused {},
+
+    #[error("Contract is not in emergency mode")]
+    NotInEmergency {},
+
+    #[error("No aUST surplus over the user ledger")]
+    NoSurplus {},
+
+    #[error("Only {available} of this asset is free to recover")]
+    RecoverExceedsFree { available: Uint128 },
 
     #[error("No Deposits to Withdraw")]
     NoDeposit {},
 
+    #[error("lock_until must be in the future")]
+    InvalidLock {},
+
+    #[error("Withdrawals wait {cooldown} seconds, use RequestWithdraw")]
+    WithdrawCooldownActive { cooldown: u64 },
+
+    #[error("No withdrawal has been requested")]
+    NoPendingWithdraw {},
+
+    #[error("Requested withdrawal can be claimed from {unlock_time}")]
+    WithdrawNotReady { unlock_time: u64 },
+
+    #[error("No claim is waiting for liquidity")]
+    NoPendingClaim {},
+
+    #[error("Anchor has no uusd to redeem aUST with right now")]
+    NoLiquidity {},
+
+    #[error("Position is locked until {until}")]
+    PositionLocked { until: u64 },
+
+    #[error("Schedule steps must be in the future and in increasing time order")]
+    InvalidSchedule {},
+
+    #[error("Donation goal must be above the {donated} already donated")]
+    InvalidDonationGoal { donated: u64 },
+
+    #[error("Cannot migrate from contract '{found}', expected '{expected}'")]
+    WrongContract { expected: String, found: String },
+
+    #[error("Cannot migrate from version {stored} down to {current}")]
+    CannotDowngrade { stored: String, current: String },
+
+    #[error("Invalid contract version: {0}")]
+    InvalidVersion(String),
+
     #[error("Wrong coin input")]
     Payment(#[from] PaymentError),
 }
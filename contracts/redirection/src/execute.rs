@@ -1,64 +1,601 @@
 use crate::{
-    msg::ExecuteMsg,
-    state::{Pool, CONFIG, USER_INFO, Config},
-    ContractError, helpers::check_funds,
+    msg::{ExecuteMsg, RecoverAsset, SurplusDestination, UpdateConfigMsg},
+    state::{
+        Pool, CONFIG, user_pools, DonationMode, DonationTrigger, PendingAdmin, MAX_THETA, PENDING_ADMIN,
+        MAX_PROTOCOL_FEE_BPS, MAX_EARLY_EXIT_PENALTY_BPS,
+        ConfigChange, PendingConfigChange, CONFIG_CHANGE_COUNT, PENDING_CONFIG_CHANGES,
+        Role, ROLES, PauseState, PAUSED, EMERGENCY_MODE, Recovery, RECOVERIES, RECOVERY_COUNT,
+        PendingWithdraw, PENDING_WITHDRAWS, PENDING_CLAIMS,
+    },
+    tax::{is_taxed_denom, TaxInfo},
+    ContractError, helpers::{
+        assert_deposit_allowed, assert_role, check_funds, has_role, query_aust_holdings,
+        query_exchange_rate, query_redeemable_aust,
+        record_donation, save_position, validate_percentage_bounds,
+    },
+    settlement::resume_giving,
 };
 use cosmwasm_std::{
-    to_binary, CosmosMsg, DepsMut, Env,
+    coin, to_binary, CosmosMsg, DepsMut, Env,
     MessageInfo, ReplyOn, Response,
-    SubMsg, Uint128, WasmMsg, Addr,
+    SubMsg, Uint128, WasmMsg, Addr, BankMsg,
 };
-use cw20::Cw20ExecuteMsg;
+use cw20::{BalanceResponse, Cw20ExecuteMsg, Cw20QueryMsg, Expiration};
+use cw_storage_plus::U64Key;
 
 pub fn update_config(
     deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    msg: UpdateConfigMsg,
+) -> Result<Response, ContractError> {
+    let mut config = CONFIG.load(deps.storage)?;
+
+    // each role check below is per field, an empty update would pass them all
+    if msg == UpdateConfigMsg::default() {
+        return Err(ContractError::EmptyConfigUpdate {});
+    }
+    if msg.theta.is_some() {
+        assert_role(deps.storage, &config, &info.sender, Role::Operator)?;
+    }
+    if msg.charity_address.is_some() {
+        assert_role(deps.storage, &config, &info.sender, Role::CharityManager)?;
+    }
+    if msg.anchor_market_address.is_some()
+        || msg.aust_token_address.is_some()
+        || msg.timelock_delay.is_some()
+        || msg.min_deposit.is_some()
+        || msg.min_percentage.is_some()
+        || msg.max_percentage.is_some()
+        || msg.max_deposit_per_user.is_some()
+        || msg.max_total_deposits.is_some()
+        || msg.protocol_fee_bps.is_some()
+        || msg.fee_collector.is_some()
+        || msg.fee_target.is_some()
+        || msg.early_exit_penalty_bps.is_some()
+        || msg.withdraw_cooldown.is_some()
+    {
+        assert_role(deps.storage, &config, &info.sender, Role::Owner)?;
+    }
+
+    let mut res = Response::new().add_attribute("method", "update_config");
+
+    if let Some(theta) = msg.theta {
+        if theta > MAX_THETA {
+            return Err(ContractError::InvalidTheta { max: MAX_THETA });
+        }
+        res = res
+            .add_attribute("old_theta", config.theta.to_string())
+            .add_attribute("new_theta", theta.to_string());
+        config.theta = theta;
+    }
+    if let Some(min_deposit) = msg.min_deposit {
+        res = res
+            .add_attribute("old_min_deposit", config.min_deposit.to_string())
+            .add_attribute("new_min_deposit", min_deposit.to_string());
+        config.min_deposit = min_deposit;
+    }
+    if msg.min_percentage.is_some() || msg.max_percentage.is_some() {
+        let min_percentage = msg.min_percentage.unwrap_or(config.min_percentage);
+        let max_percentage = msg.max_percentage.unwrap_or(config.max_percentage);
+        validate_percentage_bounds(min_percentage, max_percentage)?;
+        res = res
+            .add_attribute("old_min_percentage", config.min_percentage.to_string())
+            .add_attribute("new_min_percentage", min_percentage.to_string())
+            .add_attribute("old_max_percentage", config.max_percentage.to_string())
+            .add_attribute("new_max_percentage", max_percentage.to_string());
+        config.min_percentage = min_percentage;
+        config.max_percentage = max_percentage;
+    }
+    if let Some(cap) = msg.max_deposit_per_user {
+        let cap = if cap == 0 { None } else { Some(cap) };
+        res = res
+            .add_attribute("old_max_deposit_per_user", format!("{:?}", config.max_deposit_per_user))
+            .add_attribute("new_max_deposit_per_user", format!("{:?}", cap));
+        config.max_deposit_per_user = cap;
+    }
+    if let Some(cap) = msg.max_total_deposits {
+        let cap = if cap == 0 { None } else { Some(cap) };
+        res = res
+            .add_attribute("old_max_total_deposits", format!("{:?}", config.max_total_deposits))
+            .add_attribute("new_max_total_deposits", format!("{:?}", cap));
+        config.max_total_deposits = cap;
+    }
+    if let Some(protocol_fee_bps) = msg.protocol_fee_bps {
+        if protocol_fee_bps > MAX_PROTOCOL_FEE_BPS {
+            return Err(ContractError::InvalidProtocolFee { max: MAX_PROTOCOL_FEE_BPS });
+        }
+        res = res
+            .add_attribute("old_protocol_fee_bps", config.protocol_fee_bps.to_string())
+            .add_attribute("new_protocol_fee_bps", protocol_fee_bps.to_string());
+        config.protocol_fee_bps = protocol_fee_bps;
+    }
+    if let Some(fee_collector) = msg.fee_collector {
+        let fee_collector = deps.api.addr_validate(&fee_collector)?;
+        res = res
+            .add_attribute("old_fee_collector", format!("{:?}", config.fee_collector))
+            .add_attribute("new_fee_collector", fee_collector.as_str());
+        config.fee_collector = Some(fee_collector);
+    }
+    if let Some(fee_target) = msg.fee_target {
+        res = res
+            .add_attribute("old_fee_target", format!("{:?}", config.fee_target))
+            .add_attribute("new_fee_target", format!("{:?}", fee_target));
+        config.fee_target = fee_target;
+    }
+    if let Some(penalty_bps) = msg.early_exit_penalty_bps {
+        if penalty_bps > MAX_EARLY_EXIT_PENALTY_BPS {
+            return Err(ContractError::InvalidEarlyExitPenalty { max: MAX_EARLY_EXIT_PENALTY_BPS });
+        }
+        let penalty_bps = if penalty_bps == 0 { None } else { Some(penalty_bps) };
+        res = res
+            .add_attribute("old_early_exit_penalty_bps", format!("{:?}", config.early_exit_penalty_bps))
+            .add_attribute("new_early_exit_penalty_bps", format!("{:?}", penalty_bps));
+        config.early_exit_penalty_bps = penalty_bps;
+    }
+    CONFIG.save(deps.storage, &config)?;
+
+    // Sensitive fields wait out the timelock so depositors can exit first
+    let mut changes = vec![];
+    if let Some(charity_address) = msg.charity_address {
+        changes.push(ConfigChange::CharityAddress(deps.api.addr_validate(&charity_address)?));
+    }
+    if let Some(anchor_market_address) = msg.anchor_market_address {
+        changes.push(ConfigChange::AnchorMarketAddress(
+            deps.api.addr_validate(&anchor_market_address)?,
+        ));
+    }
+    if let Some(aust_token_address) = msg.aust_token_address {
+        changes.push(ConfigChange::AustTokenAddress(
+            deps.api.addr_validate(&aust_token_address)?,
+        ));
+    }
+    if let Some(timelock_delay) = msg.timelock_delay {
+        changes.push(ConfigChange::TimelockDelay(timelock_delay));
+    }
+    if let Some(withdraw_cooldown) = msg.withdraw_cooldown {
+        changes.push(ConfigChange::WithdrawCooldown(withdraw_cooldown));
+    }
+
+    let eta = env.block.time.seconds() + config.timelock_delay;
+    for change in changes {
+        let id = CONFIG_CHANGE_COUNT.may_load(deps.storage)?.unwrap_or_default();
+        CONFIG_CHANGE_COUNT.save(deps.storage, &(id + 1))?;
+        PENDING_CONFIG_CHANGES.save(
+            deps.storage,
+            U64Key::new(id),
+            &PendingConfigChange { id, change, eta },
+        )?;
+        res = res
+            .add_attribute("queued_change", id.to_string())
+            .add_attribute("eta", eta.to_string());
+    }
+
+    Ok(res)
+}
+
+pub fn execute_config_change(
+    deps: DepsMut,
+    env: Env,
     info: MessageInfo,
-    msg: Config,
+    id: u64,
+) -> Result<Response, ContractError> {
+    let mut config = CONFIG.load(deps.storage)?;
+    let pending = PENDING_CONFIG_CHANGES.load(deps.storage, U64Key::new(id))?;
+    // recipient changes are the charity manager's to apply as well
+    let charity_change = matches!(pending.change, ConfigChange::CharityAddress(_));
+    if !(has_role(deps.storage, &config, &info.sender, Role::Operator)?
+        || (charity_change && has_role(deps.storage, &config, &info.sender, Role::CharityManager)?))
+    {
+        return Err(ContractError::Unauthorized {});
+    }
+    if env.block.time.seconds() < pending.eta {
+        return Err(ContractError::TimelockNotExpired { id, eta: pending.eta });
+    }
+
+    let res = Response::new()
+        .add_attribute("method", "execute_config_change")
+        .add_attribute("id", id.to_string());
+    let res = match pending.change {
+        ConfigChange::CharityAddress(new) => {
+            let res = res
+                .add_attribute("old_charity_address", config.charity_address.as_str())
+                .add_attribute("new_charity_address", new.as_str());
+            config.charity_address = new;
+            res
+        }
+        ConfigChange::AnchorMarketAddress(new) => {
+            let res = res
+                .add_attribute("old_anchor_market_address", config.anchor_market_address.as_str())
+                .add_attribute("new_anchor_market_address", new.as_str());
+            config.anchor_market_address = new;
+            res
+        }
+        ConfigChange::AustTokenAddress(new) => {
+            let res = res
+                .add_attribute("old_aust_token_address", config.aust_token_address.as_str())
+                .add_attribute("new_aust_token_address", new.as_str());
+            config.aust_token_address = new;
+            res
+        }
+        ConfigChange::TimelockDelay(new) => {
+            let res = res
+                .add_attribute("old_timelock_delay", config.timelock_delay.to_string())
+                .add_attribute("new_timelock_delay", new.to_string());
+            config.timelock_delay = new;
+            res
+        }
+        ConfigChange::WithdrawCooldown(new) => {
+            let res = res
+                .add_attribute("old_withdraw_cooldown", config.withdraw_cooldown.to_string())
+                .add_attribute("new_withdraw_cooldown", new.to_string());
+            config.withdraw_cooldown = new;
+            res
+        }
+    };
+
+    CONFIG.save(deps.storage, &config)?;
+    PENDING_CONFIG_CHANGES.remove(deps.storage, U64Key::new(id));
+
+    Ok(res)
+}
+
+pub fn cancel_config_change(
+    deps: DepsMut,
+    info: MessageInfo,
+    id: u64,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    assert_role(deps.storage, &config, &info.sender, Role::Owner)?;
+
+    PENDING_CONFIG_CHANGES.load(deps.storage, U64Key::new(id))?;
+    PENDING_CONFIG_CHANGES.remove(deps.storage, U64Key::new(id));
+
+    Ok(Response::new()
+        .add_attribute("method", "cancel_config_change")
+        .add_attribute("id", id.to_string()))
+}
+
+pub fn grant_role(
+    deps: DepsMut,
+    info: MessageInfo,
+    address: String,
+    role: Role,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    assert_role(deps.storage, &config, &info.sender, Role::Owner)?;
+
+    let address = deps.api.addr_validate(&address)?;
+    let mut roles = ROLES.may_load(deps.storage, &address)?.unwrap_or_default();
+    if !roles.contains(&role) {
+        roles.push(role);
+    }
+    ROLES.save(deps.storage, &address, &roles)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "grant_role")
+        .add_attribute("address", address)
+        .add_attribute("role", format!("{:?}", role)))
+}
+
+pub fn revoke_role(
+    deps: DepsMut,
+    info: MessageInfo,
+    address: String,
+    role: Role,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    assert_role(deps.storage, &config, &info.sender, Role::Owner)?;
+
+    let address = deps.api.addr_validate(&address)?;
+    let mut roles = ROLES.may_load(deps.storage, &address)?.unwrap_or_default();
+    roles.retain(|r| *r != role);
+    if roles.is_empty() {
+        ROLES.remove(deps.storage, &address);
+    } else {
+        ROLES.save(deps.storage, &address, &roles)?;
+    }
+
+    Ok(Response::new()
+        .add_attribute("method", "revoke_role")
+        .add_attribute("address", address)
+        .add_attribute("role", format!("{:?}", role)))
+}
+
+pub fn propose_admin(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    new_admin: String,
+    expires: Option<Expiration>,
 ) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
 
     if info.sender.ne(&config.admin) {
         return Err(ContractError::Unauthorized {});
     };
+    if let Some(expires) = expires {
+        if expires.is_expired(&env.block) {
+            return Err(ContractError::AdminProposalExpired {});
+        }
+    }
 
-    CONFIG.save(deps.storage, &msg)?;
+    let new_admin = deps.api.addr_validate(&new_admin)?;
+    PENDING_ADMIN.save(
+        deps.storage,
+        &PendingAdmin {
+            new_admin: new_admin.clone(),
+            expires,
+        },
+    )?;
 
-    Ok(Response::default())
+    Ok(Response::new()
+        .add_attribute("method", "propose_admin")
+        .add_attribute("admin", config.admin)
+        .add_attribute("new_admin", new_admin))
 }
 
-pub fn deposit_pool(
+pub fn accept_admin(
     deps: DepsMut,
     env: Env,
     info: MessageInfo,
-    percentage: u16,
 ) -> Result<Response, ContractError> {
-    if percentage < 5 || percentage > 100 {
-        return Err(ContractError::WrongPercentageInput {});
+    let pending = PENDING_ADMIN
+        .may_load(deps.storage)?
+        .ok_or(ContractError::NoPendingAdmin {})?;
+
+    if info.sender.ne(&pending.new_admin) {
+        return Err(ContractError::Unauthorized {});
     };
+    if let Some(expires) = pending.expires {
+        if expires.is_expired(&env.block) {
+            return Err(ContractError::AdminProposalExpired {});
+        }
+    }
 
-    let ust_sent = check_funds(&info)?;
-    if ust_sent.u128() < 1000 {
-        return Err(ContractError::MakeNewPoolError {});
+    let mut config = CONFIG.load(deps.storage)?;
+    let old_admin = config.admin;
+    config.admin = pending.new_admin;
+    CONFIG.save(deps.storage, &config)?;
+    PENDING_ADMIN.remove(deps.storage);
+
+    Ok(Response::new()
+        .add_attribute("method", "accept_admin")
+        .add_attribute("old_admin", old_admin)
+        .add_attribute("new_admin", config.admin))
+}
+
+pub fn cancel_admin_proposal(
+    deps: DepsMut,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+
+    if info.sender.ne(&config.admin) {
+        return Err(ContractError::Unauthorized {});
+    };
+    if PENDING_ADMIN.may_load(deps.storage)?.is_none() {
+        return Err(ContractError::NoPendingAdmin {});
+    }
+
+    PENDING_ADMIN.remove(deps.storage);
+
+    Ok(Response::new().add_attribute("method", "cancel_admin_proposal"))
+}
+
+/// Owners may set either flag. A pauser may only halt deposits.
+pub fn pause(
+    deps: DepsMut,
+    info: MessageInfo,
+    deposits: bool,
+    withdrawals: bool,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let current = PAUSED.may_load(deps.storage)?.unwrap_or_default();
+
+    if !has_role(deps.storage, &config, &info.sender, Role::Owner)? {
+        assert_role(deps.storage, &config, &info.sender, Role::Pauser)?;
+        if !deposits || withdrawals != current.withdrawals {
+            return Err(ContractError::Unauthorized {});
+        }
+    }
+
+    PAUSED.save(deps.storage, &PauseState { deposits, withdrawals })?;
+
+    Ok(Response::new()
+        .add_attribute("method", "pause")
+        .add_attribute("deposits", deposits.to_string())
+        .add_attribute("withdrawals", withdrawals.to_string()))
+}
+
+pub fn set_emergency_mode(
+    deps: DepsMut,
+    info: MessageInfo,
+    enabled: bool,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    assert_role(deps.storage, &config, &info.sender, Role::Owner)?;
+
+    EMERGENCY_MODE.save(deps.storage, &enabled)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "set_emergency_mode")
+        .add_attribute("enabled", enabled.to_string()))
+}
+
+/// Sends any aUST held above the user ledger to the charity or a reserve.
+pub fn resolve_discrepancy(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    destination: SurplusDestination,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    assert_role(deps.storage, &config, &info.sender, Role::Owner)?;
+
+    let (balance, ledger) = query_aust_holdings(deps.as_ref(), &env, &config)?;
+    if balance <= ledger {
+        return Err(ContractError::NoSurplus {});
+    }
+    let surplus = balance - ledger;
+
+    let recipient = match destination {
+        SurplusDestination::Charity => config.charity_address.clone(),
+        SurplusDestination::Reserve { address } => deps.api.addr_validate(&address)?,
     };
 
+    Ok(Response::new()
+        .add_attribute("method", "resolve_discrepancy")
+        .add_attribute("audit", format!("aust_balance={},ledger_aust={}", balance, ledger))
+        .add_attribute("surplus", surplus.to_string())
+        .add_attribute("recipient", recipient.as_str())
+        .add_message(WasmMsg::Execute {
+            contract_addr: config.aust_token_address.to_string(),
+            msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                recipient: recipient.to_string(),
+                amount: Uint128::from(surplus),
+            })?,
+            funds: vec![],
+        }))
+}
+
+/// Releases tokens sent to the contract by mistake. aUST backing user
+/// positions can never be recovered.
+pub fn recover_tokens(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    asset: RecoverAsset,
+    amount: Uint128,
+    recipient: String,
+) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
+    assert_role(deps.storage, &config, &info.sender, Role::Owner)?;
+    let recipient = deps.api.addr_validate(&recipient)?;
+
+    let (asset_name, sent, msg): (String, Uint128, CosmosMsg) = match asset {
+        RecoverAsset::Native { denom } => {
+            // Every uusd flow settles inside the transaction that started it,
+            // so between transactions no uusd is owed to in-flight operations.
+            let available = deps
+                .querier
+                .query_balance(env.contract.address.as_str(), denom.as_str())?
+                .amount;
+            if amount > available {
+                return Err(ContractError::RecoverExceedsFree { available });
+            }
+            let sent = if is_taxed_denom(&denom) {
+                amount - TaxInfo::query(&deps.querier, &denom)?.compute_tax(amount)
+            } else {
+                amount
+            };
+            let msg = BankMsg::Send {
+                to_address: recipient.to_string(),
+                amount: vec![coin(sent.u128(), denom.as_str())],
+            };
+            (denom, sent, msg.into())
+        }
+        RecoverAsset::Cw20 { contract_addr } => {
+            let contract_addr = deps.api.addr_validate(&contract_addr)?;
+            let available = if contract_addr == config.aust_token_address {
+                let (balance, ledger) = query_aust_holdings(deps.as_ref(), &env, &config)?;
+                Uint128::from(balance.saturating_sub(ledger))
+            } else {
+                let balance: BalanceResponse = deps.querier.query_wasm_smart(
+                    contract_addr.as_str(),
+                    &Cw20QueryMsg::Balance {
+                        address: env.contract.address.to_string(),
+                    },
+                )?;
+                balance.balance
+            };
+            if amount > available {
+                return Err(ContractError::RecoverExceedsFree { available });
+            }
+            let msg = WasmMsg::Execute {
+                contract_addr: contract_addr.to_string(),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: recipient.to_string(),
+                    amount,
+                })?,
+                funds: vec![],
+            };
+            (contract_addr.to_string(), amount, msg.into())
+        }
+    };
+
+    let id = RECOVERY_COUNT.may_load(deps.storage)?.unwrap_or_default();
+    RECOVERY_COUNT.save(deps.storage, &(id + 1))?;
+    RECOVERIES.save(
+        deps.storage,
+        U64Key::new(id),
+        &Recovery {
+            id,
+            asset: asset_name.clone(),
+            amount: sent,
+            recipient: recipient.clone(),
+            height: env.block.height,
+            time: env.block.time.seconds(),
+        },
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("method", "recover_tokens")
+        .add_attribute("recovery_id", id.to_string())
+        .add_attribute("asset", asset_name)
+        .add_attribute("amount", sent)
+        .add_attribute("recipient", recipient)
+        .add_message(msg))
+}
+
+pub fn deposit_pool(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    percentage: u16,
+    lock_until: Option<u64>,
+) -> Result<Response, ContractError> {
+    if PAUSED.may_load(deps.storage)?.unwrap_or_default().deposits
+        || EMERGENCY_MODE.may_load(deps.storage)?.unwrap_or_default()
+    {
+        return Err(ContractError::DepositsPaused {});
+    }
+
+    let config = CONFIG.load(deps.storage)?;
+    let ust_sent = check_funds(&info)?;
+    assert_deposit_allowed(
+        deps.storage,
+        &config,
+        info.sender.as_str(),
+        percentage,
+        ust_sent.u128() as u64,
+    )?;
+    if lock_until.is_some_and(|lock_until| lock_until <= env.block.time.seconds()) {
+        return Err(ContractError::InvalidLock {});
+    }
+    // new funds restart the cooldown, neither a request nor a queued claim
+    // can be timed around a deposit
+    PENDING_WITHDRAWS.remove(deps.storage, info.sender.as_str());
+    PENDING_CLAIMS.remove(deps.storage, info.sender.as_str());
 
     // If no user exists, create a new deposit for them
-    if !USER_INFO.has(deps.storage, info.sender.as_str()) {
+    if user_pools().may_load(deps.storage, info.sender.as_str())?.is_none() {
         make_new_deposit(
             env,
             info.sender,
             percentage,
+            lock_until,
             ust_sent,
         )
     } else {
-        let user_info = USER_INFO.load(deps.storage, info.sender.as_str())?;
+        let mut user_info = user_pools().load(deps.storage, info.sender.as_str())?;
         let aust_amount = user_info.aust_amount;
         if aust_amount == 0 {
             make_new_deposit(
                 env,
                 info.sender,
                 percentage,
+                lock_until,
                 ust_sent,
             )
         } else if aust_amount <= config.theta {
@@ -73,10 +610,16 @@ pub fn deposit_pool(
                 env,
                 info.sender,
                 percentage,
+                lock_until,
                 ust_sent,
                 user_info,
             )
         } else {
+            // topping up can only extend a lock, never shorten it
+            if lock_until > user_info.lock_until {
+                user_info.lock_until = lock_until;
+                save_position(deps.storage, env.block.height, info.sender.as_str(), &user_info)?;
+            }
             update_deposit(
                 env,
                 ust_sent,
@@ -94,18 +637,255 @@ pub fn withdraw_pool(
     info: MessageInfo,
     amount: Uint128,
 ) -> Result<Response, ContractError> {
-    let depositor = deps.api.addr_validate(&info.sender.as_str())?;
-    if !USER_INFO.has(deps.storage, depositor.as_str()) {
+    let config = CONFIG.load(deps.storage)?;
+    if config.withdraw_cooldown > 0 {
+        return Err(ContractError::WithdrawCooldownActive {
+            cooldown: config.withdraw_cooldown,
+        });
+    }
+
+    start_withdrawal(deps, env, info.sender, amount)
+}
+
+/// Records a withdrawal that `ClaimWithdraw` can run once
+/// `Config.withdraw_cooldown` has passed. A new request replaces the
+/// previous one and restarts the wait, and so does a new deposit.
+pub fn request_withdraw(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    let user_info = user_pools()
+        .may_load(deps.storage, info.sender.as_str())?
+        .ok_or(ContractError::NoDeposit {})?;
+    if user_info.aust_amount == 0 {
         return Err(ContractError::NoDeposit {});
     }
 
+    let config = CONFIG.load(deps.storage)?;
+    let rate = query_exchange_rate(deps.as_ref(), &config)?;
+    let pending = PendingWithdraw {
+        amount,
+        position_value: Uint128::from(user_info.aust_amount) * rate,
+        unlock_time: env.block.time.seconds() + config.withdraw_cooldown,
+    };
+    PENDING_WITHDRAWS.save(deps.storage, info.sender.as_str(), &pending)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "request_withdraw")
+        .add_attribute("depositor", info.sender)
+        .add_attribute("withdraw_amount", amount)
+        .add_attribute("unlock_time", pending.unlock_time.to_string()))
+}
+
+/// Runs the sender's requested withdrawal once its cooldown is over.
+pub fn claim_withdraw(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    let pending = PENDING_WITHDRAWS
+        .may_load(deps.storage, info.sender.as_str())?
+        .ok_or(ContractError::NoPendingWithdraw {})?;
+    if env.block.time.seconds() < pending.unlock_time {
+        return Err(ContractError::WithdrawNotReady {
+            unlock_time: pending.unlock_time,
+        });
+    }
+    PENDING_WITHDRAWS.remove(deps.storage, info.sender.as_str());
+
+    let amount = pending.amount.min(pending.position_value);
+    start_withdrawal(deps, env, info.sender, amount)
+}
+
+/// Retries the part of the sender's withdrawals that Anchor could not
+/// redeem before. Whatever it still cannot redeem is queued again.
+pub fn execute_claim(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    let amount = PENDING_CLAIMS
+        .may_load(deps.storage, info.sender.as_str())?
+        .ok_or(ContractError::NoPendingClaim {})?;
+    PENDING_CLAIMS.remove(deps.storage, info.sender.as_str());
+
+    start_withdrawal(deps, env, info.sender, amount)
+}
+
+fn start_withdrawal(
+    deps: DepsMut,
+    env: Env,
+    sender: Addr,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    if PAUSED.may_load(deps.storage)?.unwrap_or_default().withdrawals {
+        return Err(ContractError::WithdrawalsPaused {});
+    }
+
+    let depositor = deps.api.addr_validate(sender.as_str())?;
+    let user_info = user_pools()
+        .may_load(deps.storage, depositor.as_str())?
+        .ok_or(ContractError::NoDeposit {})?;
+    // locked positions can only leave early when there is a penalty to pay
+    if let Some(until) = user_info.lock_until {
+        let config = CONFIG.load(deps.storage)?;
+        if until > env.block.time.seconds() && config.early_exit_penalty_bps.is_none() {
+            return Err(ContractError::PositionLocked { until });
+        }
+    }
+
     withdraw_deposit(deps, env, amount, depositor)
 }
 
+/// Redeems the position, or as much of it as Anchor can pay for, without
+/// re-depositing anything. Only available while the contract is in
+/// emergency mode.
+pub fn emergency_withdraw(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    if !EMERGENCY_MODE.may_load(deps.storage)?.unwrap_or_default() {
+        return Err(ContractError::NotInEmergency {});
+    }
+
+    let user_info = user_pools()
+        .may_load(deps.storage, info.sender.as_str())?
+        .ok_or(ContractError::NoDeposit {})?;
+    if user_info.aust_amount == 0 {
+        return Err(ContractError::NoDeposit {});
+    }
+    // the position leaves through emergency withdrawals now, queued ones could never be paid
+    PENDING_WITHDRAWS.remove(deps.storage, info.sender.as_str());
+    PENDING_CLAIMS.remove(deps.storage, info.sender.as_str());
+
+    // redeem what the market can pay for, the rest waits for another EmergencyWithdraw
+    let config = CONFIG.load(deps.storage)?;
+    let redeemable = query_redeemable_aust(deps.as_ref(), &config)?;
+    if redeemable == 0 {
+        return Err(ContractError::NoLiquidity {});
+    }
+    let aust_amount = user_info.aust_amount.min(redeemable);
+    let ust_amount = (user_info.ust_amount as u128 * aust_amount as u128
+        / user_info.aust_amount as u128) as u64;
+
+    Ok(Response::new().add_submessage(SubMsg {
+        id: 5,
+        msg: CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: env.contract.address.to_string(),
+            msg: to_binary(&ExecuteMsg::InternalEmergencyRedeem {
+                aust_amount,
+                ust_amount,
+                percentage: user_info.give_percentage,
+                depositor: info.sender,
+            })?,
+            funds: vec![],
+        }),
+        gas_limit: None,
+        reply_on: ReplyOn::Success,
+    }))
+}
+
+/// Switches how the sender's donation is worked out. It takes effect from
+/// the next settlement, on all yield accrued since the previous one.
+pub fn set_donation_mode(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    mode: DonationMode,
+) -> Result<Response, ContractError> {
+    let mut user_info = user_pools()
+        .may_load(deps.storage, info.sender.as_str())?
+        .ok_or(ContractError::NoDeposit {})?;
+    let old_mode = format!("{:?}", user_info.mode);
+    user_info.mode = mode;
+
+    save_position(deps.storage, env.block.height, info.sender.as_str(), &user_info)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "set_donation_mode")
+        .add_attribute("depositor", info.sender)
+        .add_attribute("old_mode", old_mode)
+        .add_attribute("new_mode", format!("{:?}", user_info.mode)))
+}
+
+/// Sets or removes the total the sender wants to donate before giving
+/// stops. A goal has to be above what was already donated, so giving
+/// paused by a reached goal resumes at its previous percentage.
+pub fn set_donation_goal(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    goal: Option<u64>,
+) -> Result<Response, ContractError> {
+    let mut user_info = user_pools()
+        .may_load(deps.storage, info.sender.as_str())?
+        .ok_or(ContractError::NoDeposit {})?;
+    if let Some(goal) = goal {
+        if goal <= user_info.total_donated {
+            return Err(ContractError::InvalidDonationGoal {
+                donated: user_info.total_donated,
+            });
+        }
+    }
+    user_info.donation_goal = goal;
+    resume_giving(&mut user_info);
+
+    save_position(deps.storage, env.block.height, info.sender.as_str(), &user_info)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "set_donation_goal")
+        .add_attribute("depositor", info.sender)
+        .add_attribute(
+            "donation_goal",
+            goal.map(|goal| goal.to_string()).unwrap_or_else(|| "none".to_string()),
+        ))
+}
+
+/// Replaces the sender's upcoming give percentage steps. Each step must lie
+/// in the future, after the one before it, and within the configured
+/// percentage bounds. An empty schedule clears it.
+pub fn set_give_schedule(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    schedule: Vec<(u64, u16)>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let mut user_info = user_pools()
+        .may_load(deps.storage, info.sender.as_str())?
+        .ok_or(ContractError::NoDeposit {})?;
+
+    let mut previous_time = env.block.time.seconds();
+    for &(effective_time, percentage) in &schedule {
+        if effective_time <= previous_time {
+            return Err(ContractError::InvalidSchedule {});
+        }
+        if percentage < config.min_percentage || percentage > config.max_percentage {
+            return Err(ContractError::WrongPercentageInput {
+                min: config.min_percentage,
+                max: config.max_percentage,
+            });
+        }
+        previous_time = effective_time;
+    }
+    user_info.give_schedule = schedule;
+
+    save_position(deps.storage, env.block.height, info.sender.as_str(), &user_info)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "set_give_schedule")
+        .add_attribute("depositor", info.sender)
+        .add_attribute("steps", user_info.give_schedule.len().to_string()))
+}
+
 pub fn make_new_deposit(
     env: Env,
     depositor: Addr,
     percentage: u16,
+    lock_until: Option<u64>,
     ust_sent: Uint128,
 ) -> Result<Response, ContractError> {
     Ok(Response::new().add_submessage(SubMsg {
@@ -115,6 +895,7 @@ pub fn make_new_deposit(
             msg: to_binary(&ExecuteMsg::InternalDepositInitial {
                 ust_sent,
                 percentage,
+                lock_until,
                 depositor,
             })?,
             funds: vec![],
@@ -129,6 +910,7 @@ pub fn send_dust_to_angel_then_make_new_deposit(
     env: Env,
     depositor: Addr,
     percentage: u16,
+    lock_until: Option<u64>,
     ust_sent: Uint128,
     user_info: Pool,
 ) -> Result<Response, ContractError> {
@@ -141,6 +923,7 @@ pub fn send_dust_to_angel_then_make_new_deposit(
             msg: to_binary(&ExecuteMsg::InternalDepositInitial {
                 ust_sent,
                 percentage,
+                lock_until,
                 depositor: depositor.clone(),
             })?,
             funds: vec![],
@@ -152,8 +935,20 @@ pub fn send_dust_to_angel_then_make_new_deposit(
     let mut new_user_info = user_info.clone();
     new_user_info.aust_amount = 0u64;
     new_user_info.ust_amount = 0u64;
+    new_user_info.unrecovered_loss = 0u64;
+    new_user_info.high_water_mark = 0u64;
+    new_user_info.pledge_shortfall = 0u64;
 
-    USER_INFO.save(deps.storage, &depositor.to_string(), &new_user_info)?;
+    save_position(deps.storage, env.block.height, depositor.as_str(), &new_user_info)?;
+    record_donation(
+        deps.storage,
+        &env,
+        depositor.as_str(),
+        &config.charity_address,
+        user_info.aust_amount,
+        config.aust_token_address.as_str(),
+        DonationTrigger::Dust,
+    )?;
 
     Ok(Response::new()
         .add_message(WasmMsg::Execute {
@@ -198,11 +993,28 @@ pub fn withdraw_deposit(
     withdraw_amount: Uint128,
     depositor: Addr,
 ) -> Result<Response, ContractError> {
-    let user_info = USER_INFO.load(deps.storage, &depositor.to_string())?;
-    let aust_amount = user_info.aust_amount;
-    let ust_amount = user_info.ust_amount;
+    let user_info = user_pools().load(deps.storage, depositor.as_str())?;
+    if user_info.aust_amount == 0 {
+        return Err(ContractError::NoDeposit {});
+    }
+    let config = CONFIG.load(deps.storage)?;
     let percentage = user_info.give_percentage;
 
+    // redeem only what the market can pay for, the reply queues the rest as a claim
+    let redeemable = query_redeemable_aust(deps.as_ref(), &config)?;
+    if redeemable == 0 {
+        // a claim is never worth more than the position, and a new one replaces the last
+        let rate = query_exchange_rate(deps.as_ref(), &config)?;
+        let claim = withdraw_amount.min(Uint128::from(user_info.aust_amount) * rate);
+        PENDING_CLAIMS.save(deps.storage, depositor.as_str(), &claim)?;
+        return Ok(Response::new()
+            .add_attribute("method", "withdraw_pool")
+            .add_attribute("queued_claim", claim));
+    }
+    let aust_amount = user_info.aust_amount.min(redeemable);
+    let ust_amount = (user_info.ust_amount as u128 * aust_amount as u128
+        / user_info.aust_amount as u128) as u64;
+
     Ok(Response::new().add_submessage(SubMsg {
         id: 3,
         msg: CosmosMsg::Wasm(WasmMsg::Execute {
@@ -1,9 +1,21 @@
 use crate::{
     error::PaymentError,
-    msg::Cw20HookMsg
+    ContractError,
+    msg::{AnchorQueryMsg, Cw20HookMsg, EpochStateResponse},
+    state::{
+        user_pools, Config, DonationBucket, Role, ROLES, DonationRecord, DonationTrigger, Pool, CHARITY_DONORS,
+        CHARITY_STATS, DONATIONS, DONATION_BUCKETS, DONATION_COUNT, TOTALS,
+        LOCKED_POSITIONS,
+    },
 };
-use cosmwasm_std::{to_binary, MessageInfo, Uint128, WasmMsg};
-use cw20::Cw20ExecuteMsg;
+use cosmwasm_std::{
+    to_binary, Addr, Decimal, Deps, Env, Fraction, MessageInfo, StdResult, Storage, Uint128, WasmMsg,
+};
+use cw_storage_plus::U64Key;
+use cw20::{BalanceResponse, Cw20ExecuteMsg, Cw20QueryMsg};
+
+/// Width of a donation statement bucket.
+pub const SECONDS_PER_DAY: u64 = 86_400;
 
 pub fn get_convert_to_ust(
     anchor_market_address: String,
@@ -22,6 +34,45 @@ pub fn get_convert_to_ust(
     };
 }
 
+/// Returns Anchor's current uusd value of one aUST.
+pub fn query_exchange_rate(deps: Deps, config: &Config) -> StdResult<Decimal> {
+    let epoch_state: EpochStateResponse = deps.querier.query_wasm_smart(
+        config.anchor_market_address.as_str(),
+        &AnchorQueryMsg::EpochState {
+            block_height: None,
+            distributed_interest: None,
+        },
+    )?;
+    Ok(epoch_state.exchange_rate)
+}
+
+/// Returns how much aUST the Anchor market has the uusd to redeem right now.
+pub fn query_redeemable_aust(deps: Deps, config: &Config) -> StdResult<u64> {
+    let liquidity = deps
+        .querier
+        .query_balance(config.anchor_market_address.as_str(), "uusd")?;
+    let rate = query_exchange_rate(deps, config)?;
+    if rate.is_zero() {
+        return Ok(0);
+    }
+
+    let redeemable = liquidity.amount.multiply_ratio(rate.denominator(), rate.numerator());
+    Ok(redeemable.u128().min(u64::MAX as u128) as u64)
+}
+
+/// Returns the contract's aUST balance on the token contract and
+/// the aUST the user ledger says it should hold.
+pub fn query_aust_holdings(deps: Deps, env: &Env, config: &Config) -> StdResult<(u64, u64)> {
+    let balance: BalanceResponse = deps.querier.query_wasm_smart(
+        config.aust_token_address.to_string(),
+        &Cw20QueryMsg::Balance {
+            address: env.contract.address.to_string(),
+        },
+    )?;
+    let ledger = TOTALS.may_load(deps.storage)?.unwrap_or_default().aust_amount;
+    Ok((balance.balance.u128() as u64, ledger))
+}
+
 /// Requires exactly one native coin sent, which matches UUSD.
 /// Returns the amount if only one denom and non-zero amount. Errors otherwise.
 pub fn check_funds(info: &MessageInfo) -> Result<Uint128, PaymentError> {
@@ -42,4 +93,204 @@ pub fn check_funds(info: &MessageInfo) -> Result<Uint128, PaymentError> {
         }
         _ => Err(PaymentError::MultipleDenoms {}),
     }
-}
\ No newline at end of file
+}
+
+/// Returns whether `address` may act as `role`.
+pub fn has_role(
+    storage: &dyn Storage,
+    config: &Config,
+    address: &Addr,
+    role: Role,
+) -> StdResult<bool> {
+    if *address == config.admin {
+        return Ok(true);
+    }
+    let roles = ROLES.may_load(storage, address)?.unwrap_or_default();
+    Ok(roles.contains(&Role::Owner) || roles.contains(&role))
+}
+
+pub fn assert_role(
+    storage: &dyn Storage,
+    config: &Config,
+    address: &Addr,
+    role: Role,
+) -> Result<(), ContractError> {
+    if !has_role(storage, config, address, role)? {
+        return Err(ContractError::Unauthorized {});
+    }
+    Ok(())
+}
+
+pub fn validate_percentage_bounds(min: u16, max: u16) -> Result<(), ContractError> {
+    if min > max || max > 100 {
+        return Err(ContractError::InvalidPercentageBounds {});
+    }
+    Ok(())
+}
+
+/// Checks a deposit of `ust_sent` against the limits in `Config`.
+pub fn assert_deposit_allowed(
+    storage: &dyn Storage,
+    config: &Config,
+    depositor: &str,
+    percentage: u16,
+    ust_sent: u64,
+) -> Result<(), ContractError> {
+    if percentage < config.min_percentage || percentage > config.max_percentage {
+        return Err(ContractError::WrongPercentageInput {
+            min: config.min_percentage,
+            max: config.max_percentage,
+        });
+    }
+    if ust_sent < config.min_deposit {
+        return Err(ContractError::DepositTooSmall { min: config.min_deposit });
+    }
+    if let Some(cap) = config.max_deposit_per_user {
+        let current = user_pools()
+            .may_load(storage, depositor)?
+            .map(|pool| pool.ust_amount)
+            .unwrap_or_default();
+        if current + ust_sent > cap {
+            return Err(ContractError::UserCapExceeded { cap });
+        }
+    }
+    if let Some(cap) = config.max_total_deposits {
+        let totals = TOTALS.may_load(storage)?.unwrap_or_default();
+        if totals.ust_amount + ust_sent > cap {
+            return Err(ContractError::TotalCapExceeded { cap });
+        }
+    }
+    Ok(())
+}
+
+/// Adds a collected protocol fee to the global totals.
+pub fn record_fee(storage: &mut dyn Storage, height: u64, amount: u64) -> StdResult<()> {
+    let mut totals = TOTALS.may_load(storage)?.unwrap_or_default();
+    totals.total_fees += amount;
+    TOTALS.save(storage, &totals, height)
+}
+
+/// Saves a position and moves the global totals by the change.
+pub fn save_position(
+    storage: &mut dyn Storage,
+    height: u64,
+    depositor: &str,
+    position: &Pool,
+) -> StdResult<()> {
+    let mut totals = TOTALS.may_load(storage)?.unwrap_or_default();
+    if let Some(old) = user_pools().may_load(storage, depositor)? {
+        totals.ust_amount -= old.ust_amount;
+        totals.aust_amount -= old.aust_amount;
+        totals.total_donated -= old.total_donated;
+    }
+    totals.ust_amount += position.ust_amount;
+    totals.aust_amount += position.aust_amount;
+    totals.total_donated += position.total_donated;
+
+    TOTALS.save(storage, &totals, height)?;
+    match position.lock_until {
+        Some(lock_until) => LOCKED_POSITIONS.save(storage, depositor, &lock_until)?,
+        None => LOCKED_POSITIONS.remove(storage, depositor),
+    }
+    user_pools().save(storage, depositor, position, height)
+}
+
+/// Appends a dated donation record to the depositor's history and,
+/// for uusd payouts, adds it to that day's statement bucket and
+/// to the charity's running stats.
+pub fn record_donation(
+    storage: &mut dyn Storage,
+    env: &Env,
+    depositor: &str,
+    charity_address: &Addr,
+    amount: u64,
+    denom: &str,
+    trigger: DonationTrigger,
+) -> StdResult<()> {
+    let id = DONATION_COUNT
+        .may_load(storage, depositor)?
+        .unwrap_or_default();
+
+    DONATIONS.save(
+        storage,
+        (depositor, U64Key::new(id)),
+        &DonationRecord {
+            id,
+            height: env.block.height,
+            time: env.block.time.seconds(),
+            amount,
+            denom: denom.to_string(),
+            charity_address: charity_address.clone(),
+            trigger,
+        },
+    )?;
+    DONATION_COUNT.save(storage, depositor, &(id + 1))?;
+
+    if denom == "uusd" {
+        add_to_bucket(storage, env.block.time.seconds(), id, depositor, charity_address, amount)?;
+        add_to_charity_stats(storage, env.block.time.seconds(), depositor, charity_address, amount)?;
+    }
+
+    Ok(())
+}
+
+fn add_to_bucket(
+    storage: &mut dyn Storage,
+    time: u64,
+    id: u64,
+    depositor: &str,
+    charity_address: &Addr,
+    amount: u64,
+) -> StdResult<()> {
+    let day = U64Key::new(time / SECONDS_PER_DAY);
+    let mut buckets = DONATION_BUCKETS
+        .may_load(storage, (depositor, day.clone()))?
+        .unwrap_or_default();
+
+    match buckets
+        .iter_mut()
+        .find(|bucket| bucket.charity_address == *charity_address)
+    {
+        Some(bucket) => {
+            bucket.amount += amount;
+            bucket.count += 1;
+            bucket.last_time = time;
+        }
+        None => buckets.push(DonationBucket {
+            charity_address: charity_address.clone(),
+            amount,
+            count: 1,
+            first_time: time,
+            last_time: time,
+            first_id: Some(id),
+        }),
+    }
+
+    DONATION_BUCKETS.save(storage, (depositor, day), &buckets)
+}
+
+fn add_to_charity_stats(
+    storage: &mut dyn Storage,
+    time: u64,
+    depositor: &str,
+    charity_address: &Addr,
+    amount: u64,
+) -> StdResult<()> {
+    let mut stats = CHARITY_STATS
+        .may_load(storage, charity_address)?
+        .unwrap_or_default();
+    let donated = CHARITY_DONORS.may_load(storage, (charity_address, depositor))?;
+
+    if donated.is_none() {
+        stats.donor_count += 1;
+    }
+    stats.total_received += amount;
+    stats.last_donation_time = time;
+
+    CHARITY_DONORS.save(
+        storage,
+        (charity_address, depositor),
+        &(donated.unwrap_or_default() + amount),
+    )?;
+    CHARITY_STATS.save(storage, charity_address, &stats)
+}
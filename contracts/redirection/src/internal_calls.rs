@@ -1,12 +1,13 @@
 use crate::{
-    msg::AnchorExecuteMsg,
-    state::CONFIG,
-    ContractError, helpers::get_convert_to_ust,
+    msg::{AnchorExecuteMsg, SwapBackUpdateMsg, WithdrawSendMsg},
+    state::{Config, DonationTrigger, CONFIG},
+    ContractError, helpers::{get_convert_to_ust, record_donation, record_fee},
+    tax::TaxInfo,
 };
 use cosmwasm_std::{
     coin, to_binary, DepsMut, Env, 
     MessageInfo, Response, Uint128, 
-    WasmMsg, BankMsg, Addr,
+    WasmMsg, BankMsg, Addr, Storage,
 };
 
 pub fn deposit_initial(
@@ -15,6 +16,7 @@ pub fn deposit_initial(
     info: MessageInfo,
     ust_sent: Uint128,
     percentage: u16,
+    lock_until: Option<u64>,
     depositor: Addr,
 ) -> Result<Response, ContractError> {
     if info.sender.ne(&env.contract.address) {
@@ -22,18 +24,24 @@ pub fn deposit_initial(
     };
 
     let config = CONFIG.load(deps.storage)?;
+    let tax = TaxInfo::query(&deps.querier, "uusd")?;
 
+    // Anchor reports the amount it actually received as deposit_amount
     let deposit_stable = AnchorExecuteMsg::DepositStable {};
     let anchor_deposit = WasmMsg::Execute {
         contract_addr: config.anchor_market_address.to_string(),
         msg: to_binary(&deposit_stable)?,
-        funds: vec![coin(ust_sent.u128(), "uusd")],
+        funds: vec![coin((ust_sent - tax.compute_tax(ust_sent)).u128(), "uusd")],
     };
 
-    Ok(Response::new()
+    let mut res = Response::new()
         .add_attribute("percentage", percentage.to_string())
         .add_attribute("ust_depositor", depositor)
-        .add_message(anchor_deposit))
+        .add_message(anchor_deposit);
+    if let Some(lock_until) = lock_until {
+        res = res.add_attribute("lock_until", lock_until.to_string());
+    }
+    Ok(res)
 }
 
 pub fn deposit_more(
@@ -71,34 +79,50 @@ pub fn swap_back_aust(
     deps: DepsMut,
     env: Env,
     info: MessageInfo,
-    to_angel: u64,
-    charity_address: Addr,
-    ust_amount: u64,
-    new_percentage: u16,
-    depositor: Addr,
+    msg: SwapBackUpdateMsg,
 ) -> Result<Response, ContractError> {
     if info.sender.ne(&env.contract.address) {
         return Err(ContractError::Unauthorized {});
     };
+    let SwapBackUpdateMsg {
+        to_angel,
+        protocol_fee,
+        charity_address,
+        ust_amount,
+        new_percentage,
+        depositor,
+    } = msg;
 
     let config = CONFIG.load(deps.storage)?;
+    let tax = TaxInfo::query(&deps.querier, "uusd")?;
+    let to_angel = tax.deduct_tax(to_angel);
 
     let mut res = Response::new()
         .add_attribute("to_angel", to_angel.to_string())
         .add_attribute("new_percentage", new_percentage.to_string())
-        .add_attribute("ust_depositor", depositor);
+        .add_attribute("ust_depositor", depositor.as_str());
     // if going to an Angel Charity add the bank msg
     if to_angel != 0 {
+        record_donation(
+            deps.storage,
+            &env,
+            depositor.as_str(),
+            &charity_address,
+            to_angel,
+            "uusd",
+            DonationTrigger::Deposit,
+        )?;
         res = res.add_message(BankMsg::Send {
             to_address: charity_address.to_string(),
             amount: vec![coin(to_angel.into(), "uusd")],
         });
     }
+    res = add_protocol_fee(deps.storage, &env, &config, &tax, res, protocol_fee)?;
     // add the anchor deposit message last in all cases
     res = res.add_message(WasmMsg::Execute {
         contract_addr: config.anchor_market_address.to_string(),
         msg: to_binary(&AnchorExecuteMsg::DepositStable {})?,
-        funds: vec![coin(ust_amount.into(), "uusd")],
+        funds: vec![coin(tax.deduct_tax(ust_amount).into(), "uusd")],
     });
 
     Ok(res)
@@ -138,21 +162,56 @@ pub fn swap_aust_ust(
         .add_message(convert_to_ust))
 }
 
+pub fn emergency_redeem(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    aust_amount: u64,
+    ust_amount: u64,
+    percentage: u16,
+    depositor: Addr,
+) -> Result<Response, ContractError> {
+    if info.sender.ne(&env.contract.address) {
+        return Err(ContractError::Unauthorized {});
+    };
+
+    let config = CONFIG.load(deps.storage)?;
+    let convert_to_ust = get_convert_to_ust(
+        config.anchor_market_address.to_string(),
+        config.aust_token_address.to_string(),
+        aust_amount,
+    );
+
+    Ok(Response::new()
+        .add_attribute("percentage", percentage.to_string())
+        .add_attribute("ust_depositor", depositor)
+        .add_attribute("ust_amount", ust_amount.to_string())
+        .add_attribute("aust_amount", aust_amount.to_string())
+        .add_message(convert_to_ust))
+}
+
 pub fn withdraw_send(
     deps: DepsMut,
     env: Env,
     info: MessageInfo,
-    withdraw_amount: u64,
-    new_ust_amount: u64,
-    to_angel_amount: u64,
-    ust_depositor: Addr,
-    charity_address: Addr,
+    msg: WithdrawSendMsg,
 ) -> Result<Response, ContractError> {
     if info.sender.ne(&env.contract.address) {
         return Err(ContractError::Unauthorized {});
     };
+    let WithdrawSendMsg {
+        withdraw_amount,
+        new_ust_amount,
+        to_angel_amount,
+        protocol_fee,
+        ust_depositor,
+        charity_address,
+    } = msg;
 
     let config = CONFIG.load(deps.storage)?;
+    let tax = TaxInfo::query(&deps.querier, "uusd")?;
+    let withdraw_amount = tax.deduct_tax(withdraw_amount);
+    let to_angel_amount = tax.deduct_tax(to_angel_amount);
 
     let withdraw_to_user = BankMsg::Send {
         to_address: ust_depositor.to_string(),
@@ -165,19 +224,58 @@ pub fn withdraw_send(
     let anchor_deposit = WasmMsg::Execute {
         contract_addr: config.anchor_market_address.to_string(),
         msg: to_binary(&AnchorExecuteMsg::DepositStable {})?,
-        funds: vec![coin(new_ust_amount.into(), "uusd")],
+        funds: vec![coin(tax.deduct_tax(new_ust_amount).into(), "uusd")],
     };
 
     let mut res = Response::new()
+        .add_attribute("withdraw_amount", withdraw_amount.to_string())
         .add_attribute("to_angel", to_angel_amount.to_string())
-        .add_attribute("ust_depositor", ust_depositor)
-        .add_message(withdraw_to_user);
+        .add_attribute("ust_depositor", ust_depositor.as_str());
+    // A penalty or pledge can take the whole slice; the bank module rejects empty sends.
+    if withdraw_amount != 0 {
+        res = res.add_message(withdraw_to_user);
+    }
     if to_angel_amount != 0 {
+        record_donation(
+            deps.storage,
+            &env,
+            ust_depositor.as_str(),
+            &charity_address,
+            to_angel_amount,
+            "uusd",
+            DonationTrigger::Withdraw,
+        )?;
         res = res.add_message(send_to_charity);
     }
+    res = add_protocol_fee(deps.storage, &env, &config, &tax, res, protocol_fee)?;
     if new_ust_amount != 0 {
         res = res.add_message(anchor_deposit);
     }
 
     Ok(res)
-}
\ No newline at end of file
+}
+
+/// Sends a non-zero protocol fee, net of tax, to the fee collector and books it in the totals.
+/// The `protocol_fee` attribute reports that same net amount.
+fn add_protocol_fee(
+    storage: &mut dyn Storage,
+    env: &Env,
+    config: &Config,
+    tax: &TaxInfo,
+    res: Response,
+    protocol_fee: u64,
+) -> Result<Response, ContractError> {
+    let protocol_fee = tax.deduct_tax(protocol_fee);
+    match &config.fee_collector {
+        Some(fee_collector) if protocol_fee != 0 => {
+            record_fee(storage, env.block.height, protocol_fee)?;
+            Ok(res
+                .add_attribute("protocol_fee", protocol_fee.to_string())
+                .add_message(BankMsg::Send {
+                    to_address: fee_collector.to_string(),
+                    amount: vec![coin(protocol_fee.into(), "uusd")],
+                }))
+        }
+        _ => Ok(res.add_attribute("protocol_fee", "0")),
+    }
+}
@@ -2,10 +2,15 @@ pub mod contract;
 pub mod execute;
 pub mod helpers;
 pub mod internal_calls;
+pub mod migrations;
 pub mod replies;
 pub mod query;
 mod error;
 pub mod msg;
+pub mod settlement;
 pub mod state;
+pub mod tax;
+#[cfg(test)]
+mod testing;
 
 pub use crate::error::ContractError;
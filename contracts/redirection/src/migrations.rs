@@ -0,0 +1,153 @@
+use crate::{
+    state::{user_pools, Pool, Totals, CONFIG, TOTALS},
+    ContractError,
+};
+use cosmwasm_std::{DepsMut, Env, Order, StdError, StdResult};
+use cw_storage_plus::Map;
+use semver::Version;
+
+type MigrationStep = fn(&mut DepsMut, &Env) -> Result<(), ContractError>;
+
+/// Steps run in order for every version newer than the stored one,
+/// up to and including the version being deployed.
+const MIGRATIONS: &[(&str, MigrationStep)] = &[("1.1.0", migrate_1_1_0)];
+
+pub fn parse_version(version: &str) -> Result<Version, ContractError> {
+    Version::parse(version).map_err(|e| ContractError::InvalidVersion(e.to_string()))
+}
+
+pub fn run_migrations(
+    deps: &mut DepsMut,
+    env: &Env,
+    stored: &Version,
+    current: &Version,
+) -> Result<Vec<String>, ContractError> {
+    let mut applied = vec![];
+    for (version, step) in MIGRATIONS {
+        let version = parse_version(version)?;
+        if version > *stored && version <= *current {
+            step(deps, env)?;
+            applied.push(version.to_string());
+        }
+    }
+    Ok(applied)
+}
+
+/// 1.0.0 kept positions in a plain map under the same namespace.
+/// Re-save each one so the indexes and snapshots are built, and
+/// seed the global totals from the result.
+fn migrate_1_1_0(deps: &mut DepsMut, env: &Env) -> Result<(), ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    CONFIG.save(deps.storage, &config)?;
+
+    let legacy: Map<&str, Pool> = Map::new("user_pool");
+    let positions = legacy
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+
+    let mut totals = Totals::default();
+    for (depositor, position) in positions {
+        let depositor = String::from_utf8(depositor).map_err(StdError::from)?;
+        totals.ust_amount += position.ust_amount;
+        totals.aust_amount += position.aust_amount;
+        totals.total_donated += position.total_donated;
+        user_pools().save(deps.storage, &depositor, &position, env.block.height)?;
+    }
+    TOTALS.save(deps.storage, &totals, env.block.height)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{contract::migrate, msg::MigrateMsg, query::get_top_donors};
+    use cosmwasm_std::testing::{mock_dependencies, mock_env};
+    use cosmwasm_std::Addr;
+    use cw_storage_plus::Item;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize)]
+    struct LegacyConfig {
+        admin: Addr,
+        charity_address: Addr,
+        anchor_market_address: Addr,
+        aust_token_address: Addr,
+        theta: u64,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct LegacyPool {
+        give_percentage: u16,
+        ust_amount: u64,
+        aust_amount: u64,
+        total_donated: u64,
+    }
+
+    fn legacy_pool(ust_amount: u64, total_donated: u64) -> LegacyPool {
+        LegacyPool {
+            give_percentage: 50,
+            ust_amount,
+            aust_amount: ust_amount * 9 / 10,
+            total_donated,
+        }
+    }
+
+    #[test]
+    fn migrate_1_1_0_backfills_positions_and_totals() {
+        let mut deps = mock_dependencies(&[]);
+        cw2::set_contract_version(&mut deps.storage, "crates.io:give", "1.0.0").unwrap();
+        let config: Item<LegacyConfig> = Item::new("state");
+        config
+            .save(
+                &mut deps.storage,
+                &LegacyConfig {
+                    admin: Addr::unchecked("admin"),
+                    charity_address: Addr::unchecked("charity"),
+                    anchor_market_address: Addr::unchecked("anchor"),
+                    aust_token_address: Addr::unchecked("aust"),
+                    theta: 1_000,
+                },
+            )
+            .unwrap();
+        let legacy: Map<&str, LegacyPool> = Map::new("user_pool");
+        legacy.save(&mut deps.storage, "alice", &legacy_pool(1_000, 30)).unwrap();
+        legacy.save(&mut deps.storage, "bob", &legacy_pool(5_000, 70)).unwrap();
+
+        migrate(deps.as_mut(), mock_env(), MigrateMsg {}).unwrap();
+
+        let config = CONFIG.load(&deps.storage).unwrap();
+        assert_eq!(config.min_deposit, 1000);
+        assert_eq!(config.max_percentage, 100);
+        assert_eq!(config.timelock_delay, 0);
+
+        let bob = user_pools().load(&deps.storage, "bob").unwrap();
+        assert_eq!(bob.ust_amount, 5_000);
+        assert_eq!(bob.unrecovered_loss, 0);
+
+        let totals = TOTALS.load(&deps.storage).unwrap();
+        assert_eq!(totals.ust_amount, 6_000);
+        assert_eq!(totals.aust_amount, 5_400);
+        assert_eq!(totals.total_donated, 100);
+
+        // the total_donated index is populated for migrated positions
+        let donors = get_top_donors(deps.as_ref(), None, None).unwrap().donors;
+        let order: Vec<_> = donors.iter().map(|donor| donor.address.as_str()).collect();
+        assert_eq!(order, vec!["bob", "alice"]);
+
+        let version = cw2::get_contract_version(&deps.storage).unwrap();
+        assert_eq!(version.version, env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn migrate_refuses_downgrade_and_other_contracts() {
+        let mut deps = mock_dependencies(&[]);
+        cw2::set_contract_version(&mut deps.storage, "crates.io:give", "9.0.0").unwrap();
+        let err = migrate(deps.as_mut(), mock_env(), MigrateMsg {});
+        assert!(matches!(err, Err(ContractError::CannotDowngrade { .. })));
+
+        cw2::set_contract_version(&mut deps.storage, "crates.io:other", "1.0.0").unwrap();
+        let err = migrate(deps.as_mut(), mock_env(), MigrateMsg {});
+        assert!(matches!(err, Err(ContractError::WrongContract { .. })));
+    }
+}
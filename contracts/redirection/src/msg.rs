@@ -1,8 +1,11 @@
-use cosmwasm_std::{Uint128, Addr};
+use cosmwasm_std::{Addr, Decimal, Uint128};
+use cw20::Expiration;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::state::Config;
+use crate::state::{
+    DonationMode, DonationRecord, FeeTarget, PauseState, PendingConfigChange, Pool, Recovery, Role,
+};
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct InstantiateMsg {
@@ -11,17 +14,98 @@ pub struct InstantiateMsg {
     pub anchor_market_address: Addr,
     pub aust_token_address: Addr,
     pub theta: u64,
+    pub timelock_delay: u64,
+    pub min_deposit: u64,
+    pub min_percentage: u16,
+    pub max_percentage: u16,
+    pub max_deposit_per_user: Option<u64>,
+    pub max_total_deposits: Option<u64>,
+    pub protocol_fee_bps: u16,
+    pub fee_collector: Option<Addr>,
+    pub fee_target: FeeTarget,
+    pub early_exit_penalty_bps: Option<u16>,
+    pub withdraw_cooldown: u64,
+}
+
+/// Fields left as `None` are unchanged. Address, timelock and cooldown changes
+/// are queued for `Config.timelock_delay` seconds, the rest apply at once.
+/// A cap of 0 removes that cap, and an early exit penalty of 0
+/// refuses early exits instead of charging for them.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct UpdateConfigMsg {
+    pub charity_address: Option<String>,
+    pub anchor_market_address: Option<String>,
+    pub aust_token_address: Option<String>,
+    pub theta: Option<u64>,
+    pub timelock_delay: Option<u64>,
+    pub min_deposit: Option<u64>,
+    pub min_percentage: Option<u16>,
+    pub max_percentage: Option<u16>,
+    pub max_deposit_per_user: Option<u64>,
+    pub max_total_deposits: Option<u64>,
+    pub protocol_fee_bps: Option<u16>,
+    pub fee_collector: Option<String>,
+    pub fee_target: Option<FeeTarget>,
+    pub early_exit_penalty_bps: Option<u16>,
+    pub withdraw_cooldown: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MigrateMsg {}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RecoverAsset {
+    Native { denom: String },
+    Cw20 { contract_addr: String },
+}
+
+/// Where aUST held above the user ledger is sent.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SurplusDestination {
+    Charity,
+    Reserve { address: String },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ExecuteMsg {
-    UpdateConfig(Config),
-    DepositPool { percentage: u16 },
+    UpdateConfig(UpdateConfigMsg),
+    ExecuteConfigChange { id: u64 },
+    CancelConfigChange { id: u64 },
+    GrantRole { address: String, role: Role },
+    RevokeRole { address: String, role: Role },
+    ProposeAdmin {
+        new_admin: String,
+        expires: Option<Expiration>,
+    },
+    AcceptAdmin {},
+    CancelAdminProposal {},
+    Pause { deposits: bool, withdrawals: bool },
+    SetEmergencyMode { enabled: bool },
+    DepositPool {
+        percentage: u16,
+        lock_until: Option<u64>,
+    },
     WithdrawPool { withdraw_amount: Uint128 },
+    RequestWithdraw { withdraw_amount: Uint128 },
+    ClaimWithdraw {},
+    ExecuteClaim {},
+    EmergencyWithdraw {},
+    SetDonationMode { mode: DonationMode },
+    SetDonationGoal { goal: Option<u64> },
+    SetGiveSchedule { schedule: Vec<(u64, u16)> },
+    ResolveDiscrepancy { destination: SurplusDestination },
+    RecoverTokens {
+        asset: RecoverAsset,
+        amount: Uint128,
+        recipient: String,
+    },
     InternalDepositInitial {
         ust_sent: Uint128,
         percentage: u16,
+        lock_until: Option<u64>,
         depositor: Addr,
     },
     InternalDepositMore {
@@ -30,13 +114,7 @@ pub enum ExecuteMsg {
         percentage: u16,
         depositor: Addr,
     },
-    InternalSwapBackUpdate {
-        to_angel: u64,
-        charity_address: Addr,
-        ust_amount: u64,
-        new_percentage: u16,
-        depositor: Addr,
-    },
+    InternalSwapBackUpdate(SwapBackUpdateMsg),
     InternalWithdrawInitial {
         withdraw_amount: Uint128,
         aust_amount: u64,
@@ -44,13 +122,33 @@ pub enum ExecuteMsg {
         percentage: u16,
         depositor: Addr,
     },
-    InternalWithdrawSend {
-        withdraw_amount: u64,
-        new_ust_amount: u64,
-        to_angel_amount: u64,
-        ust_depositor: Addr,
-        charity_address: Addr,
+    InternalEmergencyRedeem {
+        aust_amount: u64,
+        ust_amount: u64,
+        percentage: u16,
+        depositor: Addr,
     },
+    InternalWithdrawSend(WithdrawSendMsg),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SwapBackUpdateMsg {
+    pub to_angel: u64,
+    pub protocol_fee: u64,
+    pub charity_address: Addr,
+    pub ust_amount: u64,
+    pub new_percentage: u16,
+    pub depositor: Addr,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct WithdrawSendMsg {
+    pub withdraw_amount: u64,
+    pub new_ust_amount: u64,
+    pub to_angel_amount: u64,
+    pub protocol_fee: u64,
+    pub ust_depositor: Addr,
+    pub charity_address: Addr,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -59,6 +157,22 @@ pub enum AnchorExecuteMsg {
     DepositStable {},
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AnchorQueryMsg {
+    EpochState {
+        block_height: Option<u64>,
+        distributed_interest: Option<Uint128>,
+    },
+}
+
+/// Anchor reports these as 256-bit values, which fit here in practice.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct EpochStateResponse {
+    pub exchange_rate: Decimal,
+    pub aterra_supply: Uint128,
+}
+
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
@@ -69,5 +183,144 @@ pub enum Cw20HookMsg {
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum QueryMsg {
+    PendingAdmin {},
+    Roles { address: String },
+    Status {},
+    Reconcile {},
+    Recoveries {
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    PendingConfigChanges {
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
     DepositInfo { address: String },
+    /// Position as it stood at the start of `height`
+    DepositInfoAtHeight { address: String, height: u64 },
+    /// Sums of all positions at the start of `height`
+    TotalsAtHeight { height: u64 },
+    DonationHistory {
+        address: String,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    DonationStatement {
+        address: String,
+        from_time: u64,
+        to_time: u64,
+    },
+    CharityStats { charity: String },
+    ListCharityDonors {
+        charity: String,
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    TopDonors {
+        limit: Option<u32>,
+        start_after: Option<String>,
+    },
+    PendingWithdraw { address: String },
+    /// Withdrawals Anchor lacked the liquidity for, ordered by address
+    PendingClaims {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// Positions whose lock has not expired yet, ordered by address
+    LockedPositions {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct DonationHistoryResponse {
+    pub donations: Vec<DonationRecord>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CharityStatement {
+    pub charity_address: Addr,
+    pub amount: u64,
+    pub count: u32,
+    pub first_time: u64,
+    pub last_time: u64,
+}
+
+/// Covers donations made from `from_time` to `to_time` inclusive.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct DonationStatementResponse {
+    pub address: String,
+    pub from_time: u64,
+    pub to_time: u64,
+    pub charities: Vec<CharityStatement>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CharityDonor {
+    pub address: String,
+    pub amount: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CharityDonorsResponse {
+    pub donors: Vec<CharityDonor>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct DonorPosition {
+    pub address: String,
+    pub position: Pool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TopDonorsResponse {
+    pub donors: Vec<DonorPosition>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingClaim {
+    pub address: String,
+    pub amount: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingClaimsResponse {
+    pub claims: Vec<PendingClaim>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct LockedPositionsResponse {
+    pub positions: Vec<DonorPosition>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingConfigChangesResponse {
+    pub changes: Vec<PendingConfigChange>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct RolesResponse {
+    pub roles: Vec<Role>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct StatusResponse {
+    pub paused: PauseState,
+    pub emergency_mode: bool,
+}
+
+/// Compares the contract's aUST balance with the sum of all positions.
+/// At most one of `surplus` and `deficit` is non-zero.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ReconcileResponse {
+    pub aust_balance: u64,
+    pub ledger_aust: u64,
+    pub surplus: u64,
+    pub deficit: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct RecoveriesResponse {
+    pub recoveries: Vec<Recovery>,
 }
@@ -1,21 +1,549 @@
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
 use cosmwasm_std::{
-    to_binary, Binary, Deps, Env, StdResult
+    to_binary, Addr, Binary, Deps, Env, Order, StdError, StdResult
 };
-use crate::{state::{USER_INFO, Pool}, msg::QueryMsg};
+use cw_storage_plus::{Bound, PrimaryKey, U64Key};
+use crate::{
+    state::{
+        user_pools, Pool, Totals, TOTALS, PENDING_ADMIN, PENDING_CONFIG_CHANGES, CONFIG, ROLES,
+        Role, PAUSED, EMERGENCY_MODE, RECOVERIES, DONATIONS, DONATION_BUCKETS, CHARITY_STATS, CHARITY_DONORS, CharityStats,
+        LOCKED_POSITIONS, PENDING_WITHDRAWS, PENDING_CLAIMS,
+    },
+    msg::{
+        QueryMsg, DonationHistoryResponse, DonationStatementResponse, CharityStatement,
+        CharityDonor, CharityDonorsResponse, DonorPosition, TopDonorsResponse,
+        PendingConfigChangesResponse, RolesResponse, StatusResponse, ReconcileResponse,
+        RecoveriesResponse, LockedPositionsResponse, PendingClaim, PendingClaimsResponse,
+    },
+    helpers::{query_aust_holdings, SECONDS_PER_DAY},
+};
+
+// settings for pagination
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 30;
 
 #[cfg_attr(not(feature = "library"), entry_point)]
-pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
     match msg {
+        QueryMsg::PendingAdmin {} => to_binary(&PENDING_ADMIN.may_load(deps.storage)?),
+        QueryMsg::Roles { address } => to_binary(&get_roles(deps, address)?),
+        QueryMsg::Status {} => to_binary(&get_status(deps)?),
+        QueryMsg::Reconcile {} => to_binary(&reconcile(deps, env)?),
+        QueryMsg::Recoveries { start_after, limit } => {
+            to_binary(&get_recoveries(deps, start_after, limit)?)
+        }
+        QueryMsg::PendingConfigChanges { start_after, limit } => {
+            to_binary(&get_pending_config_changes(deps, start_after, limit)?)
+        }
         QueryMsg::DepositInfo { address } => to_binary(&get_deposit_info(deps, address)?),
+        QueryMsg::DepositInfoAtHeight { address, height } => {
+            to_binary(&get_deposit_info_at_height(deps, address, height)?)
+        }
+        QueryMsg::TotalsAtHeight { height } => to_binary(&get_totals_at_height(deps, height)?),
+        QueryMsg::DonationHistory {
+            address,
+            start_after,
+            limit,
+        } => to_binary(&get_donation_history(deps, address, start_after, limit)?),
+        QueryMsg::DonationStatement {
+            address,
+            from_time,
+            to_time,
+        } => to_binary(&get_donation_statement(deps, address, from_time, to_time)?),
+        QueryMsg::CharityStats { charity } => to_binary(&get_charity_stats(deps, charity)?),
+        QueryMsg::ListCharityDonors {
+            charity,
+            start_after,
+            limit,
+        } => to_binary(&list_charity_donors(deps, charity, start_after, limit)?),
+        QueryMsg::TopDonors { limit, start_after } => {
+            to_binary(&get_top_donors(deps, limit, start_after)?)
+        }
+        QueryMsg::PendingWithdraw { address } => {
+            to_binary(&PENDING_WITHDRAWS.may_load(deps.storage, &address)?)
+        }
+        QueryMsg::PendingClaims { start_after, limit } => {
+            to_binary(&get_pending_claims(deps, start_after, limit)?)
+        }
+        QueryMsg::LockedPositions { start_after, limit } => {
+            to_binary(&get_locked_positions(deps, env, start_after, limit)?)
+        }
+    }
+}
+
+/// The admin is reported as `Owner` on top of any roles granted to it.
+pub fn get_roles(
+    deps: Deps,
+    address: String,
+) -> StdResult<RolesResponse> {
+    let address = deps.api.addr_validate(&address)?;
+    let config = CONFIG.load(deps.storage)?;
+    let mut roles = ROLES.may_load(deps.storage, &address)?.unwrap_or_default();
+    if address == config.admin && !roles.contains(&Role::Owner) {
+        roles.insert(0, Role::Owner);
     }
+    Ok(RolesResponse { roles })
+}
+
+pub fn get_status(deps: Deps) -> StdResult<StatusResponse> {
+    Ok(StatusResponse {
+        paused: PAUSED.may_load(deps.storage)?.unwrap_or_default(),
+        emergency_mode: EMERGENCY_MODE.may_load(deps.storage)?.unwrap_or_default(),
+    })
+}
+
+pub fn reconcile(deps: Deps, env: Env) -> StdResult<ReconcileResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    let (aust_balance, ledger_aust) = query_aust_holdings(deps, &env, &config)?;
+    Ok(ReconcileResponse {
+        aust_balance,
+        ledger_aust,
+        surplus: aust_balance.saturating_sub(ledger_aust),
+        deficit: ledger_aust.saturating_sub(aust_balance),
+    })
+}
+
+pub fn get_recoveries(
+    deps: Deps,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<RecoveriesResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(|id| Bound::exclusive(U64Key::new(id)));
+
+    let recoveries = RECOVERIES
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| item.map(|(_, recovery)| recovery))
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(RecoveriesResponse { recoveries })
+}
+
+pub fn get_pending_config_changes(
+    deps: Deps,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<PendingConfigChangesResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(|id| Bound::exclusive(U64Key::new(id)));
+
+    let changes = PENDING_CONFIG_CHANGES
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| item.map(|(_, change)| change))
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(PendingConfigChangesResponse { changes })
 }
 
 pub fn get_deposit_info(
     deps: Deps,
     address: String,
 ) -> StdResult<Pool> {
-    let deposit_info = USER_INFO.load(deps.storage, &address)?;
+    let deposit_info = user_pools().load(deps.storage, &address)?;
     Ok(deposit_info)
-}
\ No newline at end of file
+}
+
+pub fn get_deposit_info_at_height(
+    deps: Deps,
+    address: String,
+    height: u64,
+) -> StdResult<Pool> {
+    user_pools()
+        .may_load_at_height(deps.storage, &address, height)?
+        .ok_or_else(|| StdError::not_found("Pool"))
+}
+
+pub fn get_totals_at_height(
+    deps: Deps,
+    height: u64,
+) -> StdResult<Totals> {
+    let totals = TOTALS
+        .may_load_at_height(deps.storage, height)?
+        .unwrap_or_default();
+    Ok(totals)
+}
+
+pub fn get_donation_history(
+    deps: Deps,
+    address: String,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<DonationHistoryResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(|id| Bound::exclusive(U64Key::new(id)));
+
+    let donations = DONATIONS
+        .prefix(&address)
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| item.map(|(_, record)| record))
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(DonationHistoryResponse { donations })
+}
+
+pub fn get_donation_statement(
+    deps: Deps,
+    address: String,
+    from_time: u64,
+    to_time: u64,
+) -> StdResult<DonationStatementResponse> {
+    let start = Bound::inclusive(U64Key::new(from_time / SECONDS_PER_DAY));
+    let end = Bound::inclusive(U64Key::new(to_time / SECONDS_PER_DAY));
+
+    let mut charities: Vec<CharityStatement> = vec![];
+    for item in DONATION_BUCKETS
+        .prefix(&address)
+        .range(deps.storage, Some(start), Some(end), Order::Ascending)
+    {
+        let (_, buckets) = item?;
+        let whole_day = buckets
+            .iter()
+            .all(|bucket| bucket.first_time >= from_time && bucket.last_time <= to_time);
+        if whole_day {
+            for bucket in buckets {
+                add_to_statement(
+                    &mut charities,
+                    bucket.charity_address,
+                    bucket.amount,
+                    bucket.count,
+                    bucket.first_time,
+                    bucket.last_time,
+                );
+            }
+            continue;
+        }
+
+        // a boundary day only partly in range is summed from its records
+        let day_start = buckets[0].first_time / SECONDS_PER_DAY * SECONDS_PER_DAY;
+        let day_end = day_start + SECONDS_PER_DAY;
+        let first_id = buckets
+            .iter()
+            .map(|bucket| bucket.first_id.unwrap_or_default())
+            .min()
+            .unwrap_or_default();
+        for record in DONATIONS.prefix(&address).range(
+            deps.storage,
+            Some(Bound::inclusive(U64Key::new(first_id))),
+            None,
+            Order::Ascending,
+        ) {
+            let (_, record) = record?;
+            if record.time >= day_end {
+                break;
+            }
+            let in_range = record.time >= day_start.max(from_time) && record.time <= to_time;
+            if record.denom == "uusd" && in_range {
+                add_to_statement(
+                    &mut charities,
+                    record.charity_address,
+                    record.amount,
+                    1,
+                    record.time,
+                    record.time,
+                );
+            }
+        }
+    }
+
+    Ok(DonationStatementResponse {
+        address,
+        from_time,
+        to_time,
+        charities,
+    })
+}
+
+fn add_to_statement(
+    charities: &mut Vec<CharityStatement>,
+    charity_address: Addr,
+    amount: u64,
+    count: u32,
+    first_time: u64,
+    last_time: u64,
+) {
+    match charities
+        .iter_mut()
+        .find(|entry| entry.charity_address == charity_address)
+    {
+        Some(entry) => {
+            entry.amount += amount;
+            entry.count += count;
+            entry.last_time = last_time;
+        }
+        None => charities.push(CharityStatement {
+            charity_address,
+            amount,
+            count,
+            first_time,
+            last_time,
+        }),
+    }
+}
+
+pub fn get_charity_stats(
+    deps: Deps,
+    charity: String,
+) -> StdResult<CharityStats> {
+    let charity = deps.api.addr_validate(&charity)?;
+    let stats = CHARITY_STATS
+        .may_load(deps.storage, &charity)?
+        .unwrap_or_default();
+    Ok(stats)
+}
+
+pub fn list_charity_donors(
+    deps: Deps,
+    charity: String,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<CharityDonorsResponse> {
+    let charity = deps.api.addr_validate(&charity)?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive);
+
+    let donors = CHARITY_DONORS
+        .prefix(&charity)
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (address, amount) = item?;
+            Ok(CharityDonor {
+                address: String::from_utf8(address)?,
+                amount,
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(CharityDonorsResponse { donors })
+}
+
+pub fn get_top_donors(
+    deps: Deps,
+    limit: Option<u32>,
+    start_after: Option<String>,
+) -> StdResult<TopDonorsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    // walking the index in descending order, so the cursor is the upper bound
+    let end = match start_after {
+        Some(address) => {
+            let position = user_pools().load(deps.storage, &address)?;
+            let key = (U64Key::new(position.total_donated), address.into_bytes());
+            Some(Bound::exclusive(key.joined_key()))
+        }
+        None => None,
+    };
+
+    let donors = user_pools()
+        .idx
+        .total_donated
+        .range(deps.storage, None, end, Order::Descending)
+        .take(limit)
+        .map(|item| {
+            let (address, position) = item?;
+            Ok(DonorPosition {
+                address: String::from_utf8(address)?,
+                position,
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(TopDonorsResponse { donors })
+}
+
+/// Expired locks are skipped, so a page can be shorter than `limit`
+/// even when more locked positions follow.
+pub fn get_locked_positions(
+    deps: Deps,
+    env: Env,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<LockedPositionsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive);
+    let now = env.block.time.seconds();
+
+    let positions = LOCKED_POSITIONS
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .filter(|item| !matches!(item, Ok((_, lock_until)) if *lock_until <= now))
+        .map(|item| {
+            let (address, _) = item?;
+            let address = String::from_utf8(address)?;
+            let position = user_pools().load(deps.storage, &address)?;
+            Ok(DonorPosition { address, position })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(LockedPositionsResponse { positions })
+}
+
+pub fn get_pending_claims(
+    deps: Deps,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<PendingClaimsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive);
+
+    let claims = PENDING_CLAIMS
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (address, amount) = item?;
+            Ok(PendingClaim {
+                address: String::from_utf8(address)?,
+                amount,
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(PendingClaimsResponse { claims })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        helpers::{record_donation, save_position},
+        state::{DonationRecord, DonationTrigger},
+    };
+    use cosmwasm_std::{
+        testing::{mock_dependencies, mock_env},
+        Storage, Timestamp,
+    };
+
+    fn donate_at(storage: &mut dyn Storage, time: u64, amount: u64) {
+        donate(storage, "donor", time, amount, "uusd");
+    }
+
+    fn donate(storage: &mut dyn Storage, donor: &str, time: u64, amount: u64, denom: &str) {
+        let mut env = mock_env();
+        env.block.time = Timestamp::from_seconds(time);
+        env.block.height = time / 5;
+        record_donation(
+            storage,
+            &env,
+            donor,
+            &Addr::unchecked("charity"),
+            amount,
+            denom,
+            DonationTrigger::Deposit,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn donation_history_records_when_each_donation_happened() {
+        let mut deps = mock_dependencies(&[]);
+        donate_at(&mut deps.storage, 1_000, 10);
+        donate_at(&mut deps.storage, 2_000, 20);
+        donate(&mut deps.storage, "other", 3_000, 30, "uusd");
+
+        let history = get_donation_history(deps.as_ref(), "donor".to_string(), None, None).unwrap();
+        assert_eq!(history.donations.len(), 2);
+        assert_eq!(
+            history.donations[1],
+            DonationRecord {
+                id: 1,
+                height: 400,
+                time: 2_000,
+                amount: 20,
+                denom: "uusd".to_string(),
+                charity_address: Addr::unchecked("charity"),
+                trigger: DonationTrigger::Deposit,
+            }
+        );
+
+        let page = get_donation_history(deps.as_ref(), "donor".to_string(), Some(0), Some(1)).unwrap();
+        assert_eq!(page.donations.len(), 1);
+        assert_eq!(page.donations[0].id, 1);
+    }
+
+    #[test]
+    fn statement_uses_exact_times_on_boundary_days() {
+        let mut deps = mock_dependencies(&[]);
+        let day = 10 * SECONDS_PER_DAY;
+        donate_at(&mut deps.storage, day + 100, 1);
+        donate_at(&mut deps.storage, day + 5_000, 10);
+        donate_at(&mut deps.storage, day + SECONDS_PER_DAY + 10, 100);
+        donate_at(&mut deps.storage, day + 2 * SECONDS_PER_DAY + 10, 1_000);
+        donate_at(&mut deps.storage, day + 2 * SECONDS_PER_DAY + 9_000, 10_000);
+
+        let statement = get_donation_statement(
+            deps.as_ref(),
+            "donor".to_string(),
+            day + 1_000,
+            day + 2 * SECONDS_PER_DAY + 100,
+        )
+        .unwrap();
+
+        assert_eq!(statement.charities.len(), 1);
+        assert_eq!(statement.charities[0].amount, 1_110);
+        assert_eq!(statement.charities[0].count, 3);
+        assert_eq!(statement.charities[0].first_time, day + 5_000);
+        assert_eq!(statement.charities[0].last_time, day + 2 * SECONDS_PER_DAY + 10);
+    }
+
+    #[test]
+    fn charity_stats_count_each_donor_once() {
+        let mut deps = mock_dependencies(&[]);
+        donate(&mut deps.storage, "alice", 1_000, 10, "uusd");
+        donate(&mut deps.storage, "bob", 2_000, 20, "uusd");
+        donate(&mut deps.storage, "alice", 3_000, 30, "uusd");
+        // only uusd is aggregated
+        donate(&mut deps.storage, "carol", 4_000, 40, "uluna");
+
+        let stats = get_charity_stats(deps.as_ref(), "charity".to_string()).unwrap();
+        assert_eq!(
+            stats,
+            CharityStats {
+                total_received: 60,
+                donor_count: 2,
+                last_donation_time: 3_000,
+            }
+        );
+
+        let donors = list_charity_donors(deps.as_ref(), "charity".to_string(), None, None).unwrap();
+        assert_eq!(
+            donors.donors,
+            vec![
+                CharityDonor {
+                    address: "alice".to_string(),
+                    amount: 40,
+                },
+                CharityDonor {
+                    address: "bob".to_string(),
+                    amount: 20,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn positions_and_totals_are_read_at_past_heights() {
+        let mut deps = mock_dependencies(&[]);
+        let position = |ust_amount| Pool {
+            give_percentage: 10,
+            ust_amount,
+            aust_amount: ust_amount,
+            ..Pool::default()
+        };
+        save_position(&mut deps.storage, 100, "donor", &position(1_000)).unwrap();
+        save_position(&mut deps.storage, 200, "donor", &position(3_000)).unwrap();
+        save_position(&mut deps.storage, 200, "other", &position(500)).unwrap();
+
+        // a height sees the state from before that block's changes
+        let at = |height| get_deposit_info_at_height(deps.as_ref(), "donor".to_string(), height);
+        assert!(at(100).is_err());
+        assert_eq!(at(150).unwrap().ust_amount, 1_000);
+        assert_eq!(at(200).unwrap().ust_amount, 1_000);
+        assert_eq!(at(201).unwrap().ust_amount, 3_000);
+
+        assert_eq!(get_totals_at_height(deps.as_ref(), 150).unwrap().ust_amount, 1_000);
+        assert_eq!(get_totals_at_height(deps.as_ref(), 201).unwrap().ust_amount, 3_500);
+    }
+}
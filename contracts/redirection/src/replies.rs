@@ -1,22 +1,25 @@
 use crate::{
-    msg::ExecuteMsg,
-    state::{Pool, CONFIG, USER_INFO},
-    ContractError,
+    msg::{ExecuteMsg, SwapBackUpdateMsg, WithdrawSendMsg},
+    state::{Pool, CONFIG, user_pools, DonationTrigger, PENDING_CLAIMS},
+    ContractError, helpers::{record_donation, save_position},
+    settlement::{restore_unredeemed, scale_for_withdrawal, settle, split_off},
+    tax::TaxInfo,
 };
 use cosmwasm_std::{
     coin, to_binary, ContractResult, CosmosMsg, DepsMut, Env, ReplyOn, Response,
-    SubMsg, SubMsgExecutionResponse, WasmMsg, Addr,
+    SubMsg, SubMsgExecutionResponse, WasmMsg, Addr, BankMsg, Uint128,
 };
 
 pub fn make_new_user_struct(
     deps: DepsMut,
-    _env: Env,
+    env: Env,
     msg: ContractResult<SubMsgExecutionResponse>,
 ) -> Result<Response, ContractError> {
     match msg {
         ContractResult::Ok(subcall) => {
             let mut ust_depositor = String::from("");
             let mut percentage = 0u16;
+            let mut lock_until = None;
             let mut deposit_amount = 0u64;
             let mut mint_amount = 0u64;
 
@@ -28,33 +31,35 @@ pub fn make_new_user_struct(
                         mint_amount = attrb.value.parse::<u64>().unwrap();
                     } else if attrb.key == "percentage" {
                         percentage = attrb.value.parse::<u16>().unwrap();
+                    } else if attrb.key == "lock_until" {
+                        lock_until = Some(attrb.value.parse::<u64>().unwrap());
                     } else if attrb.key == "ust_depositor" {
                         ust_depositor = attrb.value;
                     }
                 }
             }
 
-            let depositor_info;
-
-            if !USER_INFO.has(deps.storage, &ust_depositor) {
-                depositor_info = Pool {
-                    give_percentage: percentage.clone(),
-                    ust_amount: deposit_amount.clone(),
-                    aust_amount: mint_amount.clone(),
-                    total_donated: 0,
-                };
-            } else {
-                let total_donated =
-                USER_INFO.load(deps.storage, &ust_depositor)?.total_donated;
-                depositor_info = Pool {
-                    give_percentage: percentage.clone(),
-                    ust_amount: deposit_amount.clone(),
-                    aust_amount: mint_amount.clone(),
-                    total_donated,
-                };
-            }
+            // a returning depositor keeps their donation history and preferences, nothing else
+            let previous = user_pools()
+                .may_load(deps.storage, &ust_depositor)?
+                .unwrap_or_default();
+            let paused = previous.paused_percentage.is_some();
+            let depositor_info = Pool {
+                give_percentage: if paused { 0 } else { percentage },
+                paused_percentage: previous.paused_percentage.map(|_| percentage),
+                ust_amount: deposit_amount,
+                aust_amount: mint_amount,
+                total_donated: previous.total_donated,
+                high_water_mark: deposit_amount,
+                mode: previous.mode,
+                donation_goal: previous.donation_goal,
+                give_schedule: previous.give_schedule,
+                last_settled: env.block.time.seconds(),
+                lock_until,
+                ..Pool::default()
+            };
 
-            USER_INFO.save(deps.storage, &ust_depositor, &depositor_info)?;
+            save_position(deps.storage, env.block.height, &ust_depositor, &depositor_info)?;
 
             Ok(Response::new()
                 .add_attribute("give_percentage", percentage.to_string())
@@ -92,37 +97,53 @@ pub fn get_new_user_state_dep(
                 }
             }
 
-            let user_info = USER_INFO.load(deps.storage, &ust_depositor)?;
+            let mut user_info = user_pools().load(deps.storage, &ust_depositor)?;
             let config = CONFIG.load(deps.storage)?;
             let ust_amount = user_info.ust_amount;
-            let prev_percentage = user_info.give_percentage as u64;
 
-            let diff;
-            if ust_amount > redeem_amount {
-                diff = 0;
-            } else {
-                diff = redeem_amount - ust_amount;
-            }
+            let now = env.block.time.seconds();
+            let tax = TaxInfo::query(&deps.querier, "uusd")?;
+            // Anchor taxes the redemption it pays out, settle on what arrived
+            let redeem_amount = tax.deduct_tax(redeem_amount);
+            let settlement = settle(&mut user_info, redeem_amount, now, &tax, Some(&config));
+            // blend against the step in force now, not the one the window opened with
+            let prev_percentage =
+                user_info.paused_percentage.unwrap_or(user_info.give_percentage) as u64;
+            let to_angel = settlement.to_angel;
+            let protocol_fee = settlement.protocol_fee;
 
-            let to_angel = (diff * prev_percentage) / 100;
-
-            let new_ust_amount = redeem_amount + deposit_amount - to_angel;
-            let new_percentage = (((ust_amount * prev_percentage) + (deposit_amount * percentage))
+            let retained = settlement.retained(redeem_amount);
+            let new_ust_amount = retained + deposit_amount;
+            // the mark follows what reaches Anchor, the tax on the way there is not principal
+            user_info.high_water_mark =
+                (user_info.high_water_mark + tax.deduct_tax(new_ust_amount)).saturating_sub(retained);
+            let mut new_percentage = (((ust_amount * prev_percentage) + (deposit_amount * percentage))
                 / (ust_amount + deposit_amount)) as u16;
+            // a reached goal keeps giving paused, the blend is what it resumes with
+            if user_info.paused_percentage.is_some() {
+                user_info.paused_percentage = Some(new_percentage);
+                new_percentage = 0;
+            }
+
+            save_position(deps.storage, env.block.height, &ust_depositor, &user_info)?;
 
             Ok(Response::new()
+                .add_attributes(settlement.attributes(&user_info))
+                .add_events(settlement.events(&ust_depositor, &user_info))
                 .add_submessage(SubMsg {
                     id: 2,
                     msg: CosmosMsg::Wasm(WasmMsg::Execute {
                         contract_addr: env.contract.address.to_string(),
-                        msg: to_binary(&ExecuteMsg::InternalSwapBackUpdate {
+                        msg: to_binary(&ExecuteMsg::InternalSwapBackUpdate(SwapBackUpdateMsg {
                             to_angel,
+                            protocol_fee,
                             charity_address: config.charity_address,
                             ust_amount: new_ust_amount,
                             new_percentage,
                             depositor: Addr::unchecked(ust_depositor),
-                        })?,
-                        funds: vec![coin(deposit_amount.into(), "uusd")],
+                        }))?,
+                        // the deposit is already in the contract, sending it to ourselves would only pay tax
+                        funds: vec![],
                     }),
                     gas_limit: None,
                     reply_on: ReplyOn::Success,
@@ -134,7 +155,7 @@ pub fn get_new_user_state_dep(
 
 pub fn deposit_then_update_user(
     deps: DepsMut,
-    _env: Env,
+    env: Env,
     msg: ContractResult<SubMsgExecutionResponse>,
 ) -> Result<Response, ContractError> {
     match msg {
@@ -161,13 +182,13 @@ pub fn deposit_then_update_user(
                 }
             }
 
-            let mut tokens = USER_INFO.load(deps.storage, &ust_depositor)?;
+            let mut tokens = user_pools().load(deps.storage, &ust_depositor)?;
             tokens.aust_amount = mint_amount;
             tokens.ust_amount = deposit_amount;
             tokens.give_percentage = new_percentage;
             tokens.total_donated += to_angel;
 
-            USER_INFO.save(deps.storage, &ust_depositor, &tokens)?;
+            save_position(deps.storage, env.block.height, &ust_depositor, &tokens)?;
             Ok(Response::default())
         }
         ContractResult::Err(_) => Err(ContractError::Unauthorized {}),
@@ -185,6 +206,7 @@ pub fn get_new_user_state_wit(
             let mut redeem_amount = 0;
             let mut withdraw_amount = 0;
             let mut ust_amount = 0;
+            let mut aust_amount = 0;
 
             for event in subcall.events {
                 for attrb in event.attributes {
@@ -196,42 +218,74 @@ pub fn get_new_user_state_wit(
                         ust_depositor = attrb.value;
                     } else if attrb.key == "ust_amount" {
                         ust_amount = attrb.value.parse::<u64>().unwrap();
+                    } else if attrb.key == "aust_amount" {
+                        aust_amount = attrb.value.parse::<u64>().unwrap();
                     }
                 }
             }
 
-            let percentage = USER_INFO
-                .load(deps.storage, &ust_depositor)?
-                .give_percentage as u64;
-
-            let diff;
-            if ust_amount > redeem_amount {
-                diff = 0;
-            } else {
-                diff = redeem_amount - ust_amount;
-            }
+            // only the redeemed slice is settled, the rest stays in Anchor as it is
+            let mut rest = user_pools().load(deps.storage, &ust_depositor)?;
+            let mut user_info = split_off(&mut rest, ust_amount, aust_amount);
+            let config = CONFIG.load(deps.storage)?;
 
-            let to_angel_amount = (diff * percentage) / 100;
-            let max_withdrawable = redeem_amount - to_angel_amount;
+            let now = env.block.time.seconds();
+            let tax = TaxInfo::query(&deps.querier, "uusd")?;
+            // Anchor taxes the redemption it pays out, settle on what arrived
+            let redeem_amount = tax.deduct_tax(redeem_amount);
+            let settlement = settle(&mut user_info, redeem_amount, now, &tax, Some(&config));
+            let to_angel_amount = settlement.to_angel;
+            let protocol_fee = settlement.protocol_fee;
+            let max_withdrawable = settlement.retained(redeem_amount);
 
+            let requested = withdraw_amount;
             if withdraw_amount > max_withdrawable {
                 withdraw_amount = max_withdrawable;
             };
 
-            let new_ust_amount = redeem_amount - to_angel_amount - withdraw_amount;
-            let config = CONFIG.load(deps.storage)?;
+            // what Anchor could not pay out now waits as a claim
+            let mut queued_claim = 0u64;
+            if rest.aust_amount != 0 && requested > withdraw_amount {
+                // never more than the rest is worth at the rate this slice redeemed at
+                let rest_value = (redeem_amount as u128 * rest.aust_amount as u128
+                    / aust_amount as u128) as u64;
+                queued_claim = (requested - withdraw_amount).min(rest_value);
+                PENDING_CLAIMS.save(deps.storage, &ust_depositor, &Uint128::from(queued_claim))?;
+            }
+
+            let new_ust_amount = max_withdrawable - withdraw_amount;
+            scale_for_withdrawal(&mut user_info, new_ust_amount, max_withdrawable);
+            restore_unredeemed(&mut user_info, &rest);
+            save_position(deps.storage, env.block.height, &ust_depositor, &user_info)?;
 
-            Ok(Response::new().add_submessage(SubMsg {
+            // leaving a locked position early hands the penalty to its charity
+            let mut early_exit_penalty = 0u64;
+            if let Some(until) = user_info.lock_until.filter(|until| *until > now) {
+                let penalty_bps = config
+                    .early_exit_penalty_bps
+                    .ok_or(ContractError::PositionLocked { until })?;
+                early_exit_penalty = (withdraw_amount * penalty_bps as u64) / 10_000;
+            }
+            let withdraw_amount = withdraw_amount - early_exit_penalty;
+            let to_angel_amount = to_angel_amount + early_exit_penalty;
+
+            Ok(Response::new()
+                .add_attributes(settlement.attributes(&user_info))
+                .add_attribute("early_exit_penalty", early_exit_penalty.to_string())
+                .add_attribute("queued_claim", queued_claim.to_string())
+                .add_events(settlement.events(&ust_depositor, &user_info))
+                .add_submessage(SubMsg {
                 id: 4,
                 msg: CosmosMsg::Wasm(WasmMsg::Execute {
                     contract_addr: env.contract.address.to_string(),
-                    msg: to_binary(&ExecuteMsg::InternalWithdrawSend {
+                    msg: to_binary(&ExecuteMsg::InternalWithdrawSend(WithdrawSendMsg {
                         withdraw_amount,
                         new_ust_amount,
                         to_angel_amount,
+                        protocol_fee,
                         ust_depositor: Addr::unchecked(ust_depositor),
                         charity_address: config.charity_address,
-                    })?,
+                    }))?,
                     funds: vec![],
                 }),
                 gas_limit: None,
@@ -244,7 +298,7 @@ pub fn get_new_user_state_wit(
 
 pub fn withdraw_then_update_user(
     deps: DepsMut,
-    _env: Env,
+    env: Env,
     msg: ContractResult<SubMsgExecutionResponse>,
 ) -> Result<Response, ContractError> {
     match msg {
@@ -268,18 +322,108 @@ pub fn withdraw_then_update_user(
                 }
             }
 
-            let mut tokens = USER_INFO.load(deps.storage, &ust_depositor)?;
+            // the position holds only what was left unredeemed until the re-deposit is added
+            let mut tokens = user_pools().load(deps.storage, &ust_depositor)?;
+            tokens.aust_amount += mint_amount;
+            tokens.ust_amount += deposit_amount;
+            tokens.total_donated += to_angel;
+
             let config = CONFIG.load(deps.storage)?;
-            if mint_amount < config.theta {
+            if tokens.aust_amount < config.theta {
                 tokens.give_percentage = 0u16;
             }
 
-            tokens.aust_amount = mint_amount;
-            tokens.ust_amount = deposit_amount;
+            save_position(deps.storage, env.block.height, &ust_depositor, &tokens)?;
+            Ok(Response::default())
+        }
+        ContractResult::Err(_) => Err(ContractError::Unauthorized {}),
+    }
+}
+
+pub fn emergency_withdraw_then_update_user(
+    deps: DepsMut,
+    env: Env,
+    msg: ContractResult<SubMsgExecutionResponse>,
+) -> Result<Response, ContractError> {
+    match msg {
+        ContractResult::Ok(subcall) => {
+            let mut ust_depositor = String::from("");
+            let mut redeem_amount = 0u64;
+            let mut ust_amount = 0u64;
+            let mut aust_amount = 0u64;
+
+            for event in subcall.events {
+                for attrb in event.attributes {
+                    if attrb.key == "redeem_amount" {
+                        redeem_amount = attrb.value.parse::<u64>().unwrap();
+                    } else if attrb.key == "ust_amount" {
+                        ust_amount = attrb.value.parse::<u64>().unwrap();
+                    } else if attrb.key == "aust_amount" {
+                        aust_amount = attrb.value.parse::<u64>().unwrap();
+                    } else if attrb.key == "ust_depositor" {
+                        ust_depositor = attrb.value;
+                    }
+                }
+            }
+
+            // with thin liquidity only a slice is redeemed, the rest stays in Anchor
+            let mut rest = user_pools().load(deps.storage, &ust_depositor)?;
+            let mut tokens = split_off(&mut rest, ust_amount, aust_amount);
+
+            // the charity still gets its share of whatever yield was realised, no fee is taken
+            let now = env.block.time.seconds();
+            let tax = TaxInfo::query(&deps.querier, "uusd")?;
+            // Anchor taxes the redemption it pays out, settle on what arrived
+            let redeem_amount = tax.deduct_tax(redeem_amount);
+            let settlement = settle(&mut tokens, redeem_amount, now, &tax, None);
+            let to_angel = settlement.to_angel;
+            let to_user = settlement.retained(redeem_amount);
+            let loss_attributes = settlement.attributes(&tokens);
+            let goal_events = settlement.events(&ust_depositor, &tokens);
+
+            let config = CONFIG.load(deps.storage)?;
+            let to_angel = tax.deduct_tax(to_angel);
+            let to_user = tax.deduct_tax(to_user);
+            tokens.give_percentage = 0u16;
+            tokens.aust_amount = 0u64;
+            tokens.ust_amount = 0u64;
+            tokens.unrecovered_loss = 0u64;
+            tokens.high_water_mark = 0u64;
+            tokens.pledge_shortfall = 0u64;
             tokens.total_donated += to_angel;
+            restore_unredeemed(&mut tokens, &rest);
+            save_position(deps.storage, env.block.height, &ust_depositor, &tokens)?;
 
-            USER_INFO.save(deps.storage, &ust_depositor, &tokens)?;
-            Ok(Response::default())
+            let mut res = Response::new()
+                .add_attribute("method", "emergency_withdraw")
+                .add_attribute("remaining_aust", tokens.aust_amount.to_string())
+                .add_attribute("to_angel", to_angel.to_string())
+                .add_attributes(loss_attributes)
+                .add_events(goal_events)
+                .add_attribute("withdraw_amount", to_user.to_string());
+            if to_user != 0 {
+                res = res.add_message(BankMsg::Send {
+                    to_address: ust_depositor.clone(),
+                    amount: vec![coin(to_user.into(), "uusd")],
+                });
+            }
+            if to_angel != 0 {
+                record_donation(
+                    deps.storage,
+                    &env,
+                    &ust_depositor,
+                    &config.charity_address,
+                    to_angel,
+                    "uusd",
+                    DonationTrigger::Emergency,
+                )?;
+                res = res.add_message(BankMsg::Send {
+                    to_address: config.charity_address.to_string(),
+                    amount: vec![coin(to_angel.into(), "uusd")],
+                });
+            }
+
+            Ok(res)
         }
         ContractResult::Err(_) => Err(ContractError::Unauthorized {}),
     }
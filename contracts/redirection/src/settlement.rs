@@ -0,0 +1,570 @@
+use crate::{
+    helpers::SECONDS_PER_DAY,
+    state::{Config, DonationMode, FeeTarget, Pool},
+    tax::TaxInfo,
+};
+use cosmwasm_std::{Attribute, Event};
+
+pub const SECONDS_PER_YEAR: u64 = 365 * SECONDS_PER_DAY;
+
+/// Outcome of marking a position to the value its aUST redeemed for.
+pub struct Settlement {
+    /// Yield the donation is computed on, after any carried loss is refilled
+    pub gain: u64,
+    pub to_angel: u64,
+    pub protocol_fee: u64,
+    pub loss_incurred: u64,
+    pub loss_recovered: u64,
+    /// This settlement's donation completes the position's goal
+    pub goal_reached: bool,
+}
+
+impl Settlement {
+    /// What stays with the depositor out of `redeem_amount`.
+    pub fn retained(&self, redeem_amount: u64) -> u64 {
+        redeem_amount - self.to_angel - self.protocol_fee
+    }
+
+    pub fn attributes(&self, position: &Pool) -> Vec<Attribute> {
+        vec![
+            Attribute::new("loss_incurred", self.loss_incurred.to_string()),
+            Attribute::new("loss_recovered", self.loss_recovered.to_string()),
+            Attribute::new("unrecovered_loss", position.unrecovered_loss.to_string()),
+            Attribute::new("high_water_mark", position.high_water_mark.to_string()),
+            Attribute::new("pledge_shortfall", position.pledge_shortfall.to_string()),
+        ]
+    }
+
+    pub fn events(&self, depositor: &str, position: &Pool) -> Vec<Event> {
+        if !self.goal_reached {
+            return vec![];
+        }
+        vec![Event::new("goal_reached")
+            .add_attribute("depositor", depositor)
+            .add_attribute("donation_goal", position.donation_goal.unwrap_or_default().to_string())
+            .add_attribute("final_donation", self.to_angel.to_string())]
+    }
+}
+
+/// Marks `position` to `redeem_amount` and works out the donation for its
+/// mode. A value below principal is carried as `unrecovered_loss` rather
+/// than forgotten, and later yield refills it before anything is donated.
+/// The protocol fee is only taken when `fee_config` is given.
+pub fn settle(
+    position: &mut Pool,
+    redeem_amount: u64,
+    now: u64,
+    tax: &TaxInfo,
+    fee_config: Option<&Config>,
+) -> Settlement {
+    let principal = position.ust_amount;
+    // positions from before checkpoints were kept have no window to weigh
+    let elapsed = match position.last_settled {
+        0 => None,
+        last_settled => Some(now.saturating_sub(last_settled)),
+    };
+    position.last_settled = now;
+    let (weighted_percentage, window) =
+        apply_schedule(position, now - elapsed.unwrap_or_default(), now);
+
+    let mut settlement = Settlement {
+        gain: 0,
+        to_angel: 0,
+        protocol_fee: 0,
+        loss_incurred: principal.saturating_sub(redeem_amount),
+        loss_recovered: 0,
+        goal_reached: false,
+    };
+    position.unrecovered_loss += settlement.loss_incurred;
+
+    let realised = redeem_amount.saturating_sub(principal);
+    settlement.loss_recovered = realised.min(position.unrecovered_loss);
+    position.unrecovered_loss -= settlement.loss_recovered;
+
+    settlement.gain = match position.mode {
+        // only value above the best level already settled counts, which
+        // also covers any loss since the mark sits above it
+        DonationMode::HighWaterMark => {
+            redeem_amount.saturating_sub(position.high_water_mark.max(principal))
+        }
+        _ => realised - settlement.loss_recovered,
+    };
+    settlement.to_angel = match position.mode {
+        DonationMode::Pledge {
+            annual_amount,
+            use_principal,
+        } => {
+            let accrued = scale(annual_amount, elapsed.unwrap_or_default(), SECONDS_PER_YEAR);
+            let due = accrued + position.pledge_shortfall;
+            let available = if use_principal {
+                redeem_amount
+            } else {
+                settlement.gain
+            };
+            let paid = due.min(available);
+            position.pledge_shortfall = due - paid;
+            paid
+        }
+        // principal is constant over the window, every deposit and
+        // withdrawal settles first, so the target is simple interest on it
+        DonationMode::ExcessYield { retain_apy_bps } => match elapsed {
+            Some(elapsed) => {
+                let target = principal as u128 * retain_apy_bps as u128 * elapsed as u128
+                    / (10_000 * SECONDS_PER_YEAR as u128);
+                settlement.gain.saturating_sub(target.min(u64::MAX as u128) as u64)
+            }
+            None => 0,
+        },
+        _ if window == 0 => (settlement.gain * position.give_percentage as u64) / 100,
+        _ => (settlement.gain as u128 * weighted_percentage / (100 * window)) as u64,
+    };
+
+    // a fee out of the donation is capped along with it, one out of the
+    // depositor's yield is taken on whatever the goal leaves them
+    let (donation_fee, yield_fee) = match fee_config {
+        Some(config) if config.fee_target == FeeTarget::Donation => (Some(config), None),
+        config => (None, config),
+    };
+    if let Some(config) = donation_fee {
+        apply_protocol_fee(config, &mut settlement);
+    }
+    if let Some(goal) = position.donation_goal {
+        cap_to_goal(position, goal, tax, donation_fee, &mut settlement);
+    }
+    if let Some(config) = yield_fee {
+        apply_protocol_fee(config, &mut settlement);
+    }
+
+    let retained = settlement.retained(redeem_amount);
+    if retained > position.high_water_mark {
+        position.high_water_mark = retained;
+    }
+
+    settlement
+}
+
+/// Takes the protocol fee from the donation or from the depositor's share
+/// of the gain, as configured. Principal is never charged.
+fn apply_protocol_fee(config: &Config, settlement: &mut Settlement) {
+    if config.fee_collector.is_none() {
+        return;
+    }
+
+    let fee_bps = config.protocol_fee_bps as u64;
+    match config.fee_target {
+        FeeTarget::Donation => {
+            settlement.protocol_fee = (settlement.to_angel * fee_bps) / 10_000;
+            settlement.to_angel -= settlement.protocol_fee;
+        }
+        FeeTarget::UserYield => {
+            let user_yield = settlement.gain.saturating_sub(settlement.to_angel);
+            settlement.protocol_fee = (user_yield * fee_bps) / 10_000;
+        }
+    }
+}
+
+/// Moves the position onto every scheduled step that took effect by `now`
+/// and returns the give percentage summed over each second since `since`,
+/// along with the window length, so yield is split pro rata by time.
+fn apply_schedule(position: &mut Pool, since: u64, now: u64) -> (u128, u128) {
+    let due = position
+        .give_schedule
+        .iter()
+        .take_while(|(effective_time, _)| *effective_time <= now)
+        .count();
+
+    let mut current = position.give_percentage;
+    let mut cursor = since;
+    let mut weighted = 0u128;
+    for (effective_time, percentage) in position.give_schedule.drain(..due) {
+        let start = effective_time.max(since);
+        weighted += current as u128 * (start - cursor) as u128;
+        cursor = start;
+        current = percentage;
+    }
+    weighted += current as u128 * (now - cursor) as u128;
+    position.give_percentage = current;
+
+    (weighted, (now - since) as u128)
+}
+
+/// Sends only what is still missing to reach `goal`, and nothing once it
+/// is reached. `total_donated` counts what arrived after tax, so the cap
+/// includes the tax on the last transfer. Reaching the goal drops
+/// `give_percentage` to 0, the mode and schedule are kept for when the
+/// goal is raised or removed.
+fn cap_to_goal(
+    position: &mut Pool,
+    goal: u64,
+    tax: &TaxInfo,
+    fee_config: Option<&Config>,
+    settlement: &mut Settlement,
+) {
+    let remaining = goal.saturating_sub(position.total_donated);
+    let cap = tax.add_tax(remaining);
+    if settlement.to_angel < cap {
+        return;
+    }
+
+    // a fee taken out of the donation shrinks along with it
+    if let Some(Config { fee_target: FeeTarget::Donation, .. }) = fee_config {
+        settlement.protocol_fee = scale(settlement.protocol_fee, cap, settlement.to_angel);
+    }
+    settlement.to_angel = cap;
+    settlement.goal_reached = remaining != 0;
+    position.pledge_shortfall = 0;
+    // a scheduled step taken while paused becomes the percentage to resume with
+    if position.give_percentage != 0 {
+        position.paused_percentage = Some(position.give_percentage);
+    }
+    position.give_percentage = 0;
+}
+
+/// Gives again at the percentage a reached goal paused.
+pub fn resume_giving(position: &mut Pool) {
+    if let Some(percentage) = position.paused_percentage.take() {
+        position.give_percentage = percentage;
+    }
+}
+
+/// Carves the slice being redeemed out of `position`, along with its pro
+/// rata share of the high water mark and carried loss. What is left in
+/// `position` stays in Anchor untouched.
+pub fn split_off(position: &mut Pool, ust_amount: u64, aust_amount: u64) -> Pool {
+    let mut slice = position.clone();
+    if aust_amount < position.aust_amount {
+        slice.high_water_mark = scale(position.high_water_mark, aust_amount, position.aust_amount);
+        slice.unrecovered_loss = scale(position.unrecovered_loss, aust_amount, position.aust_amount);
+        // a pledge is owed once per window, the rest pays it when it settles
+        if let DonationMode::Pledge { use_principal, .. } = slice.mode {
+            slice.mode = DonationMode::Pledge {
+                annual_amount: 0,
+                use_principal,
+            };
+            slice.pledge_shortfall = 0;
+        }
+    }
+    slice.ust_amount = ust_amount;
+    slice.aust_amount = aust_amount;
+
+    position.ust_amount = position.ust_amount.saturating_sub(ust_amount);
+    position.aust_amount = position.aust_amount.saturating_sub(aust_amount);
+    position.high_water_mark -= slice.high_water_mark;
+    position.unrecovered_loss -= slice.unrecovered_loss;
+    slice
+}
+
+/// Puts the unredeemed rest back under a settled slice. The slice's own
+/// amounts are dropped, its re-deposit is added once Anchor reports it.
+/// A rest still in Anchor has not been settled, so its checkpoint,
+/// schedule and pledge carry on instead of the slice's.
+pub fn restore_unredeemed(slice: &mut Pool, rest: &Pool) {
+    if rest.aust_amount != 0 {
+        // a goal the slice reached pauses the rest as well
+        if slice.paused_percentage.is_some() {
+            slice.paused_percentage = Some(rest.paused_percentage.unwrap_or(rest.give_percentage));
+        } else {
+            slice.give_percentage = rest.give_percentage;
+        }
+        slice.last_settled = rest.last_settled;
+        slice.give_schedule = rest.give_schedule.clone();
+        slice.mode = rest.mode.clone();
+        slice.pledge_shortfall = rest.pledge_shortfall;
+    }
+    slice.ust_amount = rest.ust_amount;
+    slice.aust_amount = rest.aust_amount;
+    slice.high_water_mark += rest.high_water_mark;
+    slice.unrecovered_loss += rest.unrecovered_loss;
+}
+
+/// Shrinks position-level tracking in proportion to what a withdrawal
+/// leaves behind: `kept` out of a settled value of `value`.
+pub fn scale_for_withdrawal(position: &mut Pool, kept: u64, value: u64) {
+    position.unrecovered_loss = scale(position.unrecovered_loss, kept, value);
+    position.high_water_mark = scale(position.high_water_mark, kept, value);
+}
+
+fn scale(amount: u64, numerator: u64, denominator: u64) -> u64 {
+    if denominator == 0 {
+        return 0;
+    }
+    (amount as u128 * numerator as u128 / denominator as u128) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::{Addr, Decimal, Uint128};
+
+    const DAY: u64 = SECONDS_PER_DAY;
+    const START: u64 = 1_000 * DAY;
+
+    fn no_tax() -> TaxInfo {
+        TaxInfo {
+            rate: Decimal::zero(),
+            cap: Uint128::zero(),
+        }
+    }
+
+    fn fee_config(protocol_fee_bps: u16, fee_target: FeeTarget) -> Config {
+        Config {
+            admin: Addr::unchecked("admin"),
+            charity_address: Addr::unchecked("charity"),
+            anchor_market_address: Addr::unchecked("anchor"),
+            aust_token_address: Addr::unchecked("aust"),
+            theta: 1_000,
+            timelock_delay: 0,
+            min_deposit: 1_000,
+            min_percentage: 5,
+            max_percentage: 100,
+            max_deposit_per_user: None,
+            max_total_deposits: None,
+            protocol_fee_bps,
+            fee_collector: Some(Addr::unchecked("collector")),
+            fee_target,
+            early_exit_penalty_bps: None,
+            withdraw_cooldown: 0,
+        }
+    }
+
+    fn position(principal: u64, give_percentage: u16) -> Pool {
+        Pool {
+            give_percentage,
+            ust_amount: principal,
+            aust_amount: principal,
+            high_water_mark: principal,
+            last_settled: START,
+            ..Pool::default()
+        }
+    }
+
+    #[test]
+    fn loss_is_carried_and_refilled_before_donating() {
+        let mut pool = position(100_000_000, 50);
+
+        let settlement = settle(&mut pool, 95_000_000, START + DAY, &no_tax(), None);
+        assert_eq!(settlement.loss_incurred, 5_000_000);
+        assert_eq!(settlement.to_angel, 0);
+        assert_eq!(pool.unrecovered_loss, 5_000_000);
+        pool.ust_amount = settlement.retained(95_000_000);
+
+        let settlement = settle(&mut pool, 103_000_000, START + 2 * DAY, &no_tax(), None);
+        assert_eq!(settlement.loss_recovered, 5_000_000);
+        assert_eq!(settlement.gain, 3_000_000);
+        assert_eq!(settlement.to_angel, 1_500_000);
+        assert_eq!(pool.unrecovered_loss, 0);
+    }
+
+    #[test]
+    fn high_water_mark_only_donates_new_highs() {
+        let mut pool = position(100_000_000, 50);
+        pool.mode = DonationMode::HighWaterMark;
+
+        let settlement = settle(&mut pool, 110_000_000, START + DAY, &no_tax(), None);
+        assert_eq!(settlement.to_angel, 5_000_000);
+        assert_eq!(pool.high_water_mark, 105_000_000);
+        pool.ust_amount = settlement.retained(110_000_000);
+
+        let settlement = settle(&mut pool, 104_000_000, START + 2 * DAY, &no_tax(), None);
+        assert_eq!(settlement.to_angel, 0);
+        assert_eq!(pool.high_water_mark, 105_000_000);
+        pool.ust_amount = settlement.retained(104_000_000);
+
+        // climbing back to the mark is not yield
+        let settlement = settle(&mut pool, 107_000_000, START + 3 * DAY, &no_tax(), None);
+        assert_eq!(settlement.gain, 2_000_000);
+        assert_eq!(settlement.to_angel, 1_000_000);
+        assert_eq!(pool.high_water_mark, 106_000_000);
+    }
+
+    #[test]
+    fn pledge_shortfall_is_paid_from_later_yield() {
+        let mut pool = position(100_000_000, 0);
+        pool.mode = DonationMode::Pledge {
+            annual_amount: 365_000_000,
+            use_principal: false,
+        };
+
+        let settlement = settle(&mut pool, 100_400_000, START + DAY, &no_tax(), None);
+        assert_eq!(settlement.to_angel, 400_000);
+        assert_eq!(pool.pledge_shortfall, 600_000);
+        pool.ust_amount = settlement.retained(100_400_000);
+
+        let settlement = settle(&mut pool, 102_000_000, START + 2 * DAY, &no_tax(), None);
+        assert_eq!(settlement.to_angel, 1_600_000);
+        assert_eq!(pool.pledge_shortfall, 0);
+    }
+
+    #[test]
+    fn pledge_can_draw_on_principal() {
+        let mut pool = position(100_000_000, 0);
+        pool.mode = DonationMode::Pledge {
+            annual_amount: 365_000_000,
+            use_principal: true,
+        };
+
+        let settlement = settle(&mut pool, 100_000_000, START + DAY, &no_tax(), None);
+        assert_eq!(settlement.to_angel, 1_000_000);
+        assert_eq!(pool.pledge_shortfall, 0);
+    }
+
+    #[test]
+    fn excess_yield_keeps_the_target_return() {
+        // 10% a year on 365 UST is 0.1 UST a day
+        let mut pool = position(365_000_000, 0);
+        pool.mode = DonationMode::ExcessYield {
+            retain_apy_bps: 1_000,
+        };
+
+        let settlement = settle(&mut pool, 365_500_000, START + DAY, &no_tax(), None);
+        assert_eq!(settlement.to_angel, 400_000);
+
+        // without a checkpoint there is no window to earn the target over
+        let mut legacy = position(365_000_000, 0);
+        legacy.mode = pool.mode.clone();
+        legacy.last_settled = 0;
+        let settlement = settle(&mut legacy, 365_500_000, START + DAY, &no_tax(), None);
+        assert_eq!(settlement.to_angel, 0);
+        assert_eq!(legacy.last_settled, START + DAY);
+    }
+
+    #[test]
+    fn schedule_weights_each_step_by_time() {
+        let mut pool = position(100_000_000, 10);
+        pool.give_schedule = vec![(START + DAY, 30), (START + 5 * DAY, 50)];
+
+        // 10% for the first day and 30% for the second
+        let settlement = settle(&mut pool, 104_000_000, START + 2 * DAY, &no_tax(), None);
+        assert_eq!(settlement.to_angel, 800_000);
+        assert_eq!(pool.give_percentage, 30);
+        assert_eq!(pool.give_schedule, vec![(START + 5 * DAY, 50)]);
+    }
+
+    #[test]
+    fn goal_caps_the_donation_and_its_fee() {
+        let config = fee_config(1_000, FeeTarget::Donation);
+        let mut pool = position(100_000_000, 50);
+        pool.donation_goal = Some(20_000_000);
+
+        let settlement = settle(&mut pool, 110_000_000, START + DAY, &no_tax(), Some(&config));
+        assert!(!settlement.goal_reached);
+        assert_eq!(settlement.to_angel, 4_500_000);
+        assert_eq!(settlement.protocol_fee, 500_000);
+
+        pool.total_donated = 19_000_000;
+        pool.ust_amount = 110_000_000;
+        let settlement = settle(&mut pool, 120_000_000, START + 2 * DAY, &no_tax(), Some(&config));
+        assert!(settlement.goal_reached);
+        assert_eq!(settlement.to_angel, 1_000_000);
+        assert_eq!(settlement.protocol_fee, 111_111);
+        assert_eq!(pool.give_percentage, 0);
+        let events = settlement.events("depositor", &pool);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].ty, "goal_reached");
+    }
+
+    #[test]
+    fn yield_fee_is_taken_after_the_goal_cap() {
+        let config = fee_config(1_000, FeeTarget::UserYield);
+        let mut pool = position(100_000_000, 50);
+        pool.total_donated = 19_000_000;
+        pool.donation_goal = Some(20_000_000);
+
+        // 5 UST of the 10 UST gain was due, the goal keeps 4 UST with the depositor
+        let settlement = settle(&mut pool, 110_000_000, START + DAY, &no_tax(), Some(&config));
+        assert_eq!(settlement.to_angel, 1_000_000);
+        assert_eq!(settlement.protocol_fee, 900_000);
+    }
+
+    #[test]
+    fn partial_slice_settles_its_share() {
+        let mut rest = position(100_000_000, 50);
+        rest.aust_amount = 80_000_000;
+        rest.high_water_mark = 120_000_000;
+        rest.unrecovered_loss = 8_000_000;
+
+        let mut slice = split_off(&mut rest, 25_000_000, 20_000_000);
+        assert_eq!((slice.high_water_mark, slice.unrecovered_loss), (30_000_000, 2_000_000));
+        assert_eq!((rest.high_water_mark, rest.unrecovered_loss), (90_000_000, 6_000_000));
+        assert_eq!((rest.ust_amount, rest.aust_amount), (75_000_000, 60_000_000));
+
+        let settlement = settle(&mut slice, 30_000_000, START + DAY, &no_tax(), None);
+        assert_eq!(settlement.loss_recovered, 2_000_000);
+        assert_eq!(settlement.to_angel, 1_500_000);
+
+        restore_unredeemed(&mut slice, &rest);
+        assert_eq!(slice.unrecovered_loss, 6_000_000);
+        assert_eq!((slice.ust_amount, slice.aust_amount), (75_000_000, 60_000_000));
+    }
+
+    #[test]
+    fn goal_cap_covers_tax_on_the_last_donation() {
+        let tax = TaxInfo {
+            rate: Decimal::permille(5),
+            cap: Uint128::new(1_400_000),
+        };
+        let mut pool = position(100_000_000, 50);
+        pool.total_donated = 9_000_000;
+        pool.donation_goal = Some(10_000_000);
+
+        let settlement = settle(&mut pool, 110_000_000, START + DAY, &tax, None);
+        assert!(settlement.goal_reached);
+        assert_eq!(settlement.to_angel, 1_005_000);
+        assert_eq!(tax.deduct_tax(settlement.to_angel), 1_000_000);
+    }
+
+    #[test]
+    fn raising_the_goal_resumes_giving() {
+        let mut pool = position(100_000_000, 50);
+        pool.donation_goal = Some(1_000_000);
+
+        let settlement = settle(&mut pool, 110_000_000, START + DAY, &no_tax(), None);
+        assert!(settlement.goal_reached);
+        assert_eq!(settlement.to_angel, 1_000_000);
+        assert_eq!(pool.give_percentage, 0);
+        assert_eq!(pool.paused_percentage, Some(50));
+        pool.total_donated += settlement.to_angel;
+        pool.ust_amount = settlement.retained(110_000_000);
+
+        // reached: nothing more is given, and the event is not repeated
+        let redeem = pool.ust_amount + 2_000_000;
+        let settlement = settle(&mut pool, redeem, START + 2 * DAY, &no_tax(), None);
+        assert!(!settlement.goal_reached);
+        assert_eq!(settlement.to_angel, 0);
+        pool.ust_amount = redeem;
+
+        pool.donation_goal = None;
+        resume_giving(&mut pool);
+        assert_eq!(pool.give_percentage, 50);
+        let redeem = pool.ust_amount + 2_000_000;
+        let settlement = settle(&mut pool, redeem, START + 3 * DAY, &no_tax(), None);
+        assert_eq!(settlement.to_angel, 1_000_000);
+    }
+
+    #[test]
+    fn unredeemed_rest_keeps_its_checkpoint_and_schedule() {
+        let mut rest = position(100_000_000, 10);
+        rest.give_schedule = vec![(START + DAY, 20), (START + 10 * DAY, 30)];
+        rest.mode = DonationMode::Pledge {
+            annual_amount: 365_000,
+            use_principal: false,
+        };
+        rest.pledge_shortfall = 4_000;
+
+        let mut slice = split_off(&mut rest, 25_000_000, 25_000_000);
+        let now = START + 2 * DAY;
+        let settlement = settle(&mut slice, 26_000_000, now, &no_tax(), None);
+        // the pledge is left to the rest
+        assert_eq!(settlement.to_angel, 0);
+        assert_eq!(slice.last_settled, now);
+        assert_eq!(slice.give_schedule, vec![(START + 10 * DAY, 30)]);
+
+        restore_unredeemed(&mut slice, &rest);
+        assert_eq!(slice.aust_amount, 75_000_000);
+        assert_eq!(slice.last_settled, START);
+        assert_eq!(slice.give_percentage, 10);
+        assert_eq!(slice.give_schedule, rest.give_schedule);
+        assert_eq!(slice.mode, rest.mode);
+        assert_eq!(slice.pledge_shortfall, 4_000);
+    }
+}
@@ -1,8 +1,21 @@
-use cosmwasm_std::Addr;
-use cw_storage_plus::{Item, Map};
+use cosmwasm_std::{Addr, Uint128};
+use cw20::Expiration;
+use cw_storage_plus::{
+    Index, IndexList, IndexedSnapshotMap, Item, Map, MultiIndex, SnapshotItem, Strategy, U16Key,
+    U64Key,
+};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+/// Upper bound on `Config.protocol_fee_bps`, 10%.
+pub const MAX_PROTOCOL_FEE_BPS: u16 = 1_000;
+
+/// Upper bound on `Config.early_exit_penalty_bps`, the whole withdrawal.
+pub const MAX_EARLY_EXIT_PENALTY_BPS: u16 = 10_000;
+
+/// Upper bound on `Config.theta`, 1 aUST. Dust above this is a real balance.
+pub const MAX_THETA: u64 = 1_000_000;
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct Config {
     pub admin: Addr,
@@ -10,15 +23,285 @@ pub struct Config {
     pub anchor_market_address: Addr,
     pub aust_token_address: Addr,
     pub theta: u64,
+    /// Seconds a sensitive config change waits in the queue before it can be applied
+    #[serde(default)]
+    pub timelock_delay: u64,
+    #[serde(default = "default_min_deposit")]
+    pub min_deposit: u64,
+    #[serde(default = "default_min_percentage")]
+    pub min_percentage: u16,
+    #[serde(default = "default_max_percentage")]
+    pub max_percentage: u16,
+    #[serde(default)]
+    pub max_deposit_per_user: Option<u64>,
+    #[serde(default)]
+    pub max_total_deposits: Option<u64>,
+    #[serde(default)]
+    pub protocol_fee_bps: u16,
+    #[serde(default)]
+    pub fee_collector: Option<Addr>,
+    #[serde(default)]
+    pub fee_target: FeeTarget,
+    /// Share of a withdrawal from a locked position sent to its charity,
+    /// `None` refuses such withdrawals
+    #[serde(default)]
+    pub early_exit_penalty_bps: Option<u16>,
+    /// Seconds between `RequestWithdraw` and `ClaimWithdraw`, 0 allows `WithdrawPool`
+    #[serde(default)]
+    pub withdraw_cooldown: u64,
+}
+
+/// Which share of the realised yield the protocol fee is taken from.
+/// Principal is never charged.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeTarget {
+    #[default]
+    Donation,
+    UserYield,
+}
+
+// Limits that were hard-coded in `deposit_pool` before they moved to `Config`
+fn default_min_deposit() -> u64 {
+    1000
+}
+
+fn default_min_percentage() -> u16 {
+    5
+}
+
+fn default_max_percentage() -> u16 {
+    100
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct PauseState {
+    pub deposits: bool,
+    pub withdrawals: bool,
+}
+
+/// Privileges that the admin can hand out. The admin always holds every role,
+/// and an `Owner` passes every role check.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Owner,
+    Operator,
+    Pauser,
+    CharityManager,
+}
+
+/// A sensitive config field change held back by the timelock.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigChange {
+    CharityAddress(Addr),
+    AnchorMarketAddress(Addr),
+    AustTokenAddress(Addr),
+    TimelockDelay(u64),
+    WithdrawCooldown(u64),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingConfigChange {
+    pub id: u64,
+    pub change: ConfigChange,
+    pub eta: u64,
 }
 
-#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+/// A withdrawal waiting out `Config.withdraw_cooldown`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingWithdraw {
+    pub amount: Uint128,
+    /// uusd value of the whole position when the request was made.
+    /// The claim never pays out more than this.
+    pub position_value: Uint128,
+    pub unlock_time: u64,
+}
+
+/// Admin handover waiting for the proposed address to accept it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingAdmin {
+    pub new_admin: Addr,
+    pub expires: Option<Expiration>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, JsonSchema, Debug)]
 pub struct Pool {
     pub give_percentage: u16,
     pub ust_amount: u64,
     pub aust_amount: u64,
     pub total_donated: u64,
+    /// Principal lost to a redeemed value below `ust_amount`, refilled from yield before any donation
+    #[serde(default)]
+    pub unrecovered_loss: u64,
+    /// Highest value the position has been settled at, moved by deposits and withdrawals
+    #[serde(default)]
+    pub high_water_mark: u64,
+    #[serde(default)]
+    pub mode: DonationMode,
+    /// Block time in seconds of the last settlement, 0 for positions that predate it
+    #[serde(default)]
+    pub last_settled: u64,
+    /// Pledged amount that yield could not cover yet, owed at the next settlement
+    #[serde(default)]
+    pub pledge_shortfall: u64,
+    /// Giving stops once `total_donated` reaches this
+    #[serde(default)]
+    pub donation_goal: Option<u64>,
+    /// `give_percentage` from before the goal was reached, restored when
+    /// the goal is raised or removed
+    #[serde(default)]
+    pub paused_percentage: Option<u16>,
+    /// Upcoming `(effective_time, give_percentage)` steps, oldest first
+    #[serde(default)]
+    pub give_schedule: Vec<(u64, u16)>,
+    /// Block time in seconds before which withdrawals are refused or penalised
+    #[serde(default)]
+    pub lock_until: Option<u64>,
+}
+
+/// How a position's donation is worked out at each settlement.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, JsonSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DonationMode {
+    /// `give_percentage` of all yield since the last settlement
+    #[default]
+    Percentage,
+    /// `give_percentage` of value above the high water mark only
+    HighWaterMark,
+    /// A fixed yearly amount paid pro rata from yield, dipping into
+    /// principal only when `use_principal` is set
+    Pledge { annual_amount: u64, use_principal: bool },
+    /// Everything above a time-weighted `retain_apy_bps` return on principal
+    ExcessYield { retain_apy_bps: u32 },
+}
+
+/// Sums of every position, kept alongside `user_pools` for snapshots.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct Totals {
+    pub ust_amount: u64,
+    pub aust_amount: u64,
+    pub total_donated: u64,
+    #[serde(default)]
+    pub total_fees: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DonationTrigger {
+    Deposit,
+    Withdraw,
+    Dust,
+    Emergency,
+}
+
+/// A single payout to a charity, kept per depositor for receipts.
+/// `denom` is "uusd" except for dust, which is sent as aUST.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct DonationRecord {
+    pub id: u64,
+    pub height: u64,
+    pub time: u64,
+    pub amount: u64,
+    pub denom: String,
+    pub charity_address: Addr,
+    pub trigger: DonationTrigger,
+}
+
+/// Per-charity donation totals for one depositor over one calendar day.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct DonationBucket {
+    pub charity_address: Addr,
+    pub amount: u64,
+    pub count: u32,
+    pub first_time: u64,
+    pub last_time: u64,
+    /// `DonationRecord.id` of the first donation in the bucket, `None` for
+    /// buckets written before it was kept
+    #[serde(default)]
+    pub first_id: Option<u64>,
+}
+
+/// Cumulative uusd received by a charity through this contract.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct CharityStats {
+    pub total_received: u64,
+    pub donor_count: u32,
+    pub last_donation_time: u64,
+}
+
+/// Audit entry for tokens the admin pulled out of the contract.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Recovery {
+    pub id: u64,
+    pub asset: String,
+    /// What the recipient received, net of any transfer tax
+    pub amount: Uint128,
+    pub recipient: Addr,
+    pub height: u64,
+    pub time: u64,
 }
 
 pub const CONFIG: Item<Config> = Item::new("state");
-pub const USER_INFO: Map<&str, Pool> = Map::new("user_pool");
+pub const PAUSED: Item<PauseState> = Item::new("paused");
+pub const EMERGENCY_MODE: Item<bool> = Item::new("emergency_mode");
+pub const PENDING_WITHDRAWS: Map<&str, PendingWithdraw> = Map::new("pending_withdraws");
+/// uusd each depositor asked for that Anchor could not redeem at the time
+pub const PENDING_CLAIMS: Map<&str, Uint128> = Map::new("pending_claims");
+/// `lock_until` of every position that was ever locked, kept in step by `save_position`
+pub const LOCKED_POSITIONS: Map<&str, u64> = Map::new("locked_positions");
+pub const ROLES: Map<&Addr, Vec<Role>> = Map::new("roles");
+pub const CONFIG_CHANGE_COUNT: Item<u64> = Item::new("config_change_count");
+pub const PENDING_CONFIG_CHANGES: Map<U64Key, PendingConfigChange> =
+    Map::new("pending_config_changes");
+pub const PENDING_ADMIN: Item<PendingAdmin> = Item::new("pending_admin");
+pub const TOTALS: SnapshotItem<Totals> = SnapshotItem::new(
+    "totals",
+    "totals__checkpoints",
+    "totals__changelog",
+    Strategy::EveryBlock,
+);
+pub const DONATION_COUNT: Map<&str, u64> = Map::new("donation_count");
+pub const DONATIONS: Map<(&str, U64Key), DonationRecord> = Map::new("donations");
+pub const DONATION_BUCKETS: Map<(&str, U64Key), Vec<DonationBucket>> = Map::new("donation_buckets");
+pub const CHARITY_STATS: Map<&Addr, CharityStats> = Map::new("charity_stats");
+pub const CHARITY_DONORS: Map<(&Addr, &str), u64> = Map::new("charity_donors");
+pub const RECOVERY_COUNT: Item<u64> = Item::new("recovery_count");
+pub const RECOVERIES: Map<U64Key, Recovery> = Map::new("recoveries");
+
+pub struct PoolIndexes<'a> {
+    pub total_donated: MultiIndex<'a, (U64Key, Vec<u8>), Pool>,
+    pub give_percentage: MultiIndex<'a, (U16Key, Vec<u8>), Pool>,
+}
+
+impl<'a> IndexList<Pool> for PoolIndexes<'a> {
+    fn get_indexes(&'_ self) -> Box<dyn Iterator<Item = &'_ dyn Index<Pool>> + '_> {
+        let v: Vec<&dyn Index<Pool>> = vec![&self.total_donated, &self.give_percentage];
+        Box::new(v.into_iter())
+    }
+}
+
+/// Positions keyed by depositor, indexed by `total_donated` and `give_percentage`
+/// and snapshotted every block. Write through `helpers::save_position`.
+pub fn user_pools<'a>() -> IndexedSnapshotMap<'a, &'a str, Pool, PoolIndexes<'a>> {
+    let indexes = PoolIndexes {
+        total_donated: MultiIndex::new(
+            |pool, pk| (U64Key::new(pool.total_donated), pk),
+            "user_pool",
+            "user_pool__total_donated",
+        ),
+        give_percentage: MultiIndex::new(
+            |pool, pk| (U16Key::new(pool.give_percentage), pk),
+            "user_pool",
+            "user_pool__give_percentage",
+        ),
+    };
+    IndexedSnapshotMap::new(
+        "user_pool",
+        "user_pool__checkpoints",
+        "user_pool__changelog",
+        Strategy::EveryBlock,
+        indexes,
+    )
+}
@@ -0,0 +1,131 @@
+use cosmwasm_std::{Decimal, Fraction, QuerierWrapper, StdResult, Uint128};
+use terra_cosmwasm::TerraQuerier;
+
+/// Terra only taxes transfers of its stablecoins, e.g. `uusd` or `ukrw`.
+/// Luna and IBC denoms are sent as is.
+pub fn is_taxed_denom(denom: &str) -> bool {
+    denom != "uluna" && denom.len() == 4 && denom.starts_with('u')
+}
+
+/// Terra stability tax parameters for one native denom.
+/// Query once per message and reuse for every transfer it builds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TaxInfo {
+    pub rate: Decimal,
+    pub cap: Uint128,
+}
+
+impl TaxInfo {
+    pub fn query(querier: &QuerierWrapper, denom: &str) -> StdResult<TaxInfo> {
+        let terra_querier = TerraQuerier::new(querier);
+        Ok(TaxInfo {
+            rate: terra_querier.query_tax_rate()?.rate,
+            cap: terra_querier.query_tax_cap(denom)?.cap,
+        })
+    }
+
+    /// Tax charged when `amount` is everything that may leave the contract,
+    /// i.e. the transfer itself plus its tax.
+    pub fn compute_tax(&self, amount: Uint128) -> Uint128 {
+        let numerator = self.rate.denominator();
+        let denominator = self.rate.denominator() + self.rate.numerator();
+        let tax = amount - amount.multiply_ratio(numerator, denominator);
+        tax.min(self.cap)
+    }
+
+    /// Largest transfer that can be made out of `amount` once tax is paid.
+    pub fn deduct_tax(&self, amount: u64) -> u64 {
+        let amount = Uint128::from(amount);
+        (amount - self.compute_tax(amount)).u128() as u64
+    }
+
+    /// Smallest amount that still leaves `amount` once tax is paid,
+    /// the inverse of `deduct_tax`.
+    pub fn add_tax(&self, amount: u64) -> u64 {
+        let numerator = self.rate.numerator();
+        let denominator = self.rate.denominator();
+        let tax = (amount as u128 * numerator).div_ceil(denominator);
+        amount + tax.min(self.cap.u128()) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::mock_dependencies_with_tax;
+    use cosmwasm_std::QuerierWrapper;
+    use std::str::FromStr;
+
+    fn tax_info(rate: &str, cap: u128) -> TaxInfo {
+        TaxInfo {
+            rate: Decimal::from_str(rate).unwrap(),
+            cap: Uint128::new(cap),
+        }
+    }
+
+    #[test]
+    fn query_reads_rate_and_cap() {
+        let deps = mock_dependencies_with_tax(Decimal::permille(5), 1_400_000, &[]);
+        let info = TaxInfo::query(&QuerierWrapper::new(&deps.querier), "uusd").unwrap();
+        assert_eq!(info, tax_info("0.005", 1_400_000));
+    }
+
+    #[test]
+    fn tax_is_charged_on_the_transfer_not_the_total() {
+        let info = tax_info("0.005", 1_400_000);
+        assert_eq!(
+            info.compute_tax(Uint128::new(1_005_000)),
+            Uint128::new(5_000)
+        );
+        assert_eq!(info.deduct_tax(1_005_000), 1_000_000);
+
+        // Rounding always leaves enough behind to pay the tax on what is sent.
+        for amount in [7u64, 999, 1_000_001, 123_456_789] {
+            let sent = info.deduct_tax(amount);
+            let tax = Uint128::from(sent) * info.rate;
+            assert!(sent + tax.u128() as u64 <= amount);
+        }
+    }
+
+    #[test]
+    fn tax_is_capped() {
+        let info = tax_info("0.005", 1_400_000);
+        assert_eq!(
+            info.compute_tax(Uint128::new(1_000_000_000_000)),
+            Uint128::new(1_400_000)
+        );
+        assert_eq!(info.deduct_tax(1_000_000_000_000), 999_998_600_000);
+    }
+
+    #[test]
+    fn add_tax_is_the_smallest_gross_amount() {
+        let info = tax_info("0.005", 1_400_000);
+        for amount in [1u64, 2, 999, 1_000_000, 123_456_789, 1_000_000_000_000] {
+            let gross = info.add_tax(amount);
+            assert_eq!(info.deduct_tax(gross), amount);
+            assert!(info.deduct_tax(gross - 1) < amount);
+        }
+        assert_eq!(info.add_tax(1_000_000), 1_005_000);
+        assert_eq!(info.add_tax(0), 0);
+    }
+
+    #[test]
+    fn tiny_amounts_round_down_to_nothing() {
+        let info = tax_info("0.005", 1_400_000);
+        assert_eq!(info.deduct_tax(0), 0);
+        assert_eq!(info.deduct_tax(1), 0);
+
+        let untaxed = tax_info("0", 1_400_000);
+        assert_eq!(untaxed.deduct_tax(1), 1);
+    }
+
+    #[test]
+    fn only_terra_stablecoins_are_taxed() {
+        assert!(is_taxed_denom("uusd"));
+        assert!(is_taxed_denom("ukrw"));
+        assert!(!is_taxed_denom("uluna"));
+        assert!(!is_taxed_denom(
+            "ibc/27394FB092D2ECCD56123C74F36E4C1F926001CEADA9CA97EA622B25F41E5EB2"
+        ));
+    }
+}
@@ -0,0 +1,130 @@
+use cosmwasm_std::testing::{MockApi, MockQuerier, MockStorage, MOCK_CONTRACT_ADDR};
+use cosmwasm_std::{
+    from_binary, from_slice, to_binary, Coin, ContractResult, Decimal, OwnedDeps, Querier,
+    QuerierResult, QueryRequest, SystemError, SystemResult, Uint128, WasmQuery,
+};
+use cw20::{BalanceResponse, Cw20QueryMsg};
+use terra_cosmwasm::{TaxCapResponse, TaxRateResponse, TerraQuery, TerraQueryWrapper};
+
+use crate::msg::{AnchorQueryMsg, EpochStateResponse};
+
+/// Address the contract tests instantiate as the Anchor market.
+pub const MOCK_ANCHOR_MARKET: &str = "anchor";
+/// Address the contract tests instantiate as the aUST token.
+pub const MOCK_AUST_TOKEN: &str = "aust";
+
+fn terra_querier(
+    rate: Decimal,
+    cap: u128,
+    balances: &[(&str, &[Coin])],
+) -> MockQuerier<TerraQueryWrapper> {
+    MockQuerier::<TerraQueryWrapper>::new(balances).with_custom_handler(move |query| {
+        match &query.query_data {
+            TerraQuery::TaxRate {} => {
+                SystemResult::Ok(ContractResult::Ok(to_binary(&TaxRateResponse { rate }).unwrap()))
+            }
+            TerraQuery::TaxCap { .. } => SystemResult::Ok(ContractResult::Ok(
+                to_binary(&TaxCapResponse {
+                    cap: Uint128::new(cap),
+                })
+                .unwrap(),
+            )),
+            _ => SystemResult::Err(SystemError::UnsupportedRequest {
+                kind: "terra".to_string(),
+            }),
+        }
+    })
+}
+
+/// Mock dependencies whose querier answers Terra treasury queries with a
+/// fixed tax rate and cap.
+pub fn mock_dependencies_with_tax(
+    rate: Decimal,
+    cap: u128,
+    contract_balance: &[Coin],
+) -> OwnedDeps<MockStorage, MockApi, MockQuerier<TerraQueryWrapper>> {
+    OwnedDeps {
+        storage: MockStorage::default(),
+        api: MockApi::default(),
+        querier: terra_querier(rate, cap, &[(MOCK_CONTRACT_ADDR, contract_balance)]),
+    }
+}
+
+/// Terra querier that also answers Anchor's `EpochState` at a fixed rate
+/// and the aUST token's `Balance` with a single balance for every address.
+pub struct AnchorQuerier {
+    base: MockQuerier<TerraQueryWrapper>,
+    exchange_rate: Decimal,
+    aust_balance: u128,
+}
+
+impl AnchorQuerier {
+    pub fn set_aust_balance(&mut self, balance: u128) {
+        self.aust_balance = balance;
+    }
+}
+
+impl Querier for AnchorQuerier {
+    fn raw_query(&self, bin_request: &[u8]) -> QuerierResult {
+        let request: QueryRequest<TerraQueryWrapper> = match from_slice(bin_request) {
+            Ok(request) => request,
+            Err(e) => {
+                return SystemResult::Err(SystemError::InvalidRequest {
+                    error: e.to_string(),
+                    request: bin_request.into(),
+                })
+            }
+        };
+        match &request {
+            QueryRequest::Wasm(WasmQuery::Smart { contract_addr, msg })
+                if contract_addr == MOCK_ANCHOR_MARKET =>
+            {
+                match from_binary(msg) {
+                    Ok(AnchorQueryMsg::EpochState { .. }) => {
+                        let response = EpochStateResponse {
+                            exchange_rate: self.exchange_rate,
+                            aterra_supply: Uint128::zero(),
+                        };
+                        SystemResult::Ok(ContractResult::Ok(to_binary(&response).unwrap()))
+                    }
+                    Err(e) => SystemResult::Ok(ContractResult::Err(e.to_string())),
+                }
+            }
+            QueryRequest::Wasm(WasmQuery::Smart { contract_addr, msg })
+                if contract_addr == MOCK_AUST_TOKEN =>
+            {
+                match from_binary(msg) {
+                    Ok(Cw20QueryMsg::Balance { .. }) => {
+                        let response = BalanceResponse {
+                            balance: Uint128::new(self.aust_balance),
+                        };
+                        SystemResult::Ok(ContractResult::Ok(to_binary(&response).unwrap()))
+                    }
+                    Ok(_) => SystemResult::Err(SystemError::UnsupportedRequest {
+                        kind: "cw20".to_string(),
+                    }),
+                    Err(e) => SystemResult::Ok(ContractResult::Err(e.to_string())),
+                }
+            }
+            _ => self.base.handle_query(&request),
+        }
+    }
+}
+
+/// Mock dependencies with no tax and an Anchor market holding
+/// `market_liquidity` at the given aUST exchange rate. The contract
+/// holds no aUST until `set_aust_balance` is called.
+pub fn mock_dependencies_with_anchor(
+    exchange_rate: Decimal,
+    market_liquidity: &[Coin],
+) -> OwnedDeps<MockStorage, MockApi, AnchorQuerier> {
+    OwnedDeps {
+        storage: MockStorage::default(),
+        api: MockApi::default(),
+        querier: AnchorQuerier {
+            base: terra_querier(Decimal::zero(), 0, &[(MOCK_ANCHOR_MARKET, market_liquidity)]),
+            exchange_rate,
+            aust_balance: 0,
+        },
+    }
+}